@@ -0,0 +1,7087 @@
+//! Crawl a site through its robots.txt, sitemaps or links and save each page as Markdown.
+//!
+//! [`Crawler`] runs whole crawls; [`html_to_markdown`] converts a single page.
+
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use encoding_rs::{Encoding, UTF_8};
+use flate2::read::GzDecoder;
+use futures::future;
+use futures::stream::{self, StreamExt};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{debug, error, info, warn};
+use quick_xml::name::{LocalName, Namespace, ResolveResult};
+use quick_xml::{NsReader, Reader, events::Event};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use spider::reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use spider::url::Url;
+use spider::website::Website;
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions, create_dir_all};
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit, broadcast};
+
+// Pages per write during the crawl unless flush_every says otherwise, so a killed run
+// keeps what it fetched and visited.json lists it for --resume
+const DEFAULT_FLUSH_EVERY: usize = 20;
+
+// Names the crawler version in every page it renders
+const GENERATOR: &str = concat!("web-crawler ", env!("CARGO_PKG_VERSION"));
+
+const DEFAULT_USER_AGENT: &str = concat!(
+    "web-crawler/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/ozoneRatchapon/web-crawler)"
+);
+
+/// Settings threaded through the crawl pipeline; loadable from a TOML file via --config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CrawlerConfig {
+    pub output_dir: PathBuf,
+    pub format: OutputFormat,
+    /// Extension for each page's file in place of the format's own (md for Markdown,
+    /// txt for text, html for html-clean)
+    pub extension: Option<String>,
+    /// Lay Markdown files out like the site's URL paths (docs/guide/intro.md) instead
+    /// of flattening them into one directory (docs_guide_intro.md)
+    pub preserve_structure: bool,
+    /// Write every page into this one Markdown file, in crawl order, instead of a
+    /// file per page
+    pub single_file: Option<PathBuf>,
+    /// Write pages out, and record them in visited.json, every this many, in crawl
+    /// order, and drop their Markdown from the `CrawlResult`. 0 writes them every 20
+    /// pages, keeping the Markdown of pages saved to files of their own.
+    pub flush_every: usize,
+    pub fetch_attempts: u32,
+    /// Consecutive failed requests (timeouts, server errors) after which a host's
+    /// remaining URLs are skipped for `breaker_cooldown_secs`; 0 never skips them
+    pub breaker_failures: u32,
+    /// How long an open circuit breaker skips its host's URLs before trying it again
+    pub breaker_cooldown_secs: u64,
+    /// Longest wait, in seconds, honoured from a 429 response's Retry-After header
+    /// before fetching the page again
+    pub max_retry_after_secs: u64,
+    /// Pause between requests to the same host; robots.txt's Crawl-delay wins when
+    /// longer. Unset, the native crawl still waits 100ms and everything else none.
+    pub delay_ms: Option<u64>,
+    /// Move each pause between a host's requests by a random amount within this
+    /// percent of it (at most 100), so requests do not arrive like clockwork. The
+    /// native crawl keeps its even pace.
+    pub jitter_percent: u32,
+    /// Seed for the jitter, to repeat a run's delays; unset, every run differs
+    pub jitter_seed: Option<u64>,
+    pub concurrency: usize,
+    /// Page fetches in flight per host
+    pub per_host: usize,
+    /// Sitemaps fetched in parallel while expanding sitemap indexes
+    pub sitemap_concurrency: usize,
+    /// Levels of sitemap index nesting followed below the sitemaps listed in
+    /// robots.txt or passed in; 0 reads those sitemaps but none they point to
+    pub sitemap_max_depth: usize,
+    pub max_pages: Option<usize>,
+    /// Redirect hops followed per request before it fails
+    pub max_redirects: usize,
+    /// Give up on a robots, sitemap or page request after this many seconds
+    pub timeout_secs: u64,
+    /// Pages with a larger body are skipped rather than converted. The body has
+    /// already been downloaded by then; set `prefetch_head` to skip a page whose
+    /// Content-Length is over the limit before fetching it
+    pub max_convert_bytes: usize,
+    /// Deepest URL path (in segments) the native crawl fallback follows links to;
+    /// 0 crawls only the seed page
+    pub depth: usize,
+    /// Let the native crawl follow links onto subdomains of the site (docs.example.com
+    /// from example.com) instead of only the seed host and its www. twin
+    pub allow_subdomains: bool,
+    /// Stop the native crawl once it has found this many links
+    pub max_links: Option<usize>,
+    /// Reading speed behind each page's reading_minutes
+    pub words_per_minute: usize,
+    /// Warn about pages whose converted text is shorter than this many characters,
+    /// as when scripts render the content, and list them in the report; 0 never does
+    pub min_content_chars: usize,
+    /// Only let the native crawl visit URL paths under these prefixes, e.g. "/docs";
+    /// each prefix is crawled starting from that path
+    pub crawl_prefixes: Vec<String>,
+    /// Sent with every request; some sites require a contact address in it
+    pub user_agent: String,
+    /// With two or more entries, requests take turns using these User-Agents instead
+    /// of `user_agent`; a single entry replaces it. robots.txt is read once per site,
+    /// with the rules for the first entry.
+    pub user_agents: Vec<String>,
+    /// Extra request headers, e.g. Authorization for sites behind a login; values are
+    /// never logged
+    pub headers: BTreeMap<String, String>,
+    /// Sent as the Cookie header for the crawled domain, e.g. "session=abc123"
+    pub cookie: Option<String>,
+    /// Route every request through this http://, https:// or socks5:// proxy; unset,
+    /// HTTPS_PROXY or HTTP_PROXY is used if present
+    pub proxy: Option<String>,
+    /// Domain substrings whose pages need browser rendering, e.g. "www.heygoody.com"
+    pub browser_domains: Vec<String>,
+    /// Drop <nav>, <header>, <footer> and <aside> content when converting
+    pub strip_boilerplate: bool,
+    /// Save only the first of several pages whose Markdown is identical
+    pub dedup_content: bool,
+    /// Honour <meta name="robots">: skip saving noindex pages, and keep the native
+    /// crawl from following the links on nofollow ones
+    pub meta_robots: bool,
+    /// Save each page's images under images/ and point the Markdown at them
+    pub assets: bool,
+    /// Keep URLs that are not pages, such as PDFs, as raw files under assets/
+    pub include_assets: bool,
+    /// Send a HEAD first and skip URLs whose Content-Type or Content-Length rules
+    /// them out, without downloading the body
+    pub prefetch_head: bool,
+    /// Write a sha256sum-style <page>.md.sha256 next to each Markdown file
+    pub checksums: bool,
+    /// Also save the HTML each page was converted from, as <name>.html beside it
+    pub save_html: bool,
+    /// Begin each page's Markdown with a table of contents linking to its headings
+    pub toc: bool,
+    /// Demote every heading in the Markdown by this many levels, down to h6, e.g. so
+    /// pages merged into a single file sit under their own title
+    pub heading_offset: u8,
+    /// Keep javascript:, empty and bare # link targets, which lead nowhere, instead of
+    /// writing just the link text
+    pub keep_dead_links: bool,
+    /// List the images image sitemaps name in images.txt; they are never fetched
+    pub collect_images: bool,
+    /// Skip URLs saved by a previous run, as recorded in visited.json
+    pub resume: bool,
+    /// Compare each page's content hash with the one the manifest kept from the last
+    /// crawl and list the added, modified and removed URLs in changes.json
+    pub diff_previous: bool,
+    /// Only crawl pages whose sitemap lastmod is on or after this date (YYYY-MM-DD)
+    pub since: Option<NaiveDate>,
+    /// With `since`, also skip pages the sitemap gives no lastmod for
+    pub skip_undated: bool,
+    /// List the URLs that would be fetched instead of crawling them
+    pub dry_run: bool,
+    /// Sitemaps read directly instead of discovering them through robots.txt
+    pub sitemaps: Vec<String>,
+    /// Exact page URLs to fetch; a domain with any of these skips robots.txt and
+    /// sitemap discovery and fetches just them
+    pub urls: Vec<String>,
+    /// Read sitemaps robots.txt lists on other domains; by default only sitemaps under
+    /// the site's own registrable domain (cdn.example.com for example.com) are read
+    pub cross_domain_sitemaps: bool,
+    /// Glob patterns matched against URL paths; exclude wins over include
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Query parameters dropped from URLs before deduplication; a trailing * matches a prefix
+    pub tracking_params: Vec<String>,
+}
+
+impl Default for CrawlerConfig {
+    fn default() -> Self {
+        Self {
+            output_dir: PathBuf::from("output"),
+            format: OutputFormat::default(),
+            extension: None,
+            preserve_structure: false,
+            single_file: None,
+            flush_every: 0,
+            fetch_attempts: 3,
+            max_retry_after_secs: 60,
+            breaker_failures: 10,
+            breaker_cooldown_secs: 60,
+            delay_ms: None,
+            jitter_percent: 0,
+            jitter_seed: None,
+            concurrency: 8,
+            per_host: 4,
+            sitemap_concurrency: 4,
+            sitemap_max_depth: 10,
+            max_pages: None,
+            max_redirects: 10,
+            timeout_secs: 30,
+            max_convert_bytes: 10 * 1024 * 1024,
+            depth: 3,
+            allow_subdomains: false,
+            max_links: None,
+            words_per_minute: 200,
+            min_content_chars: 50,
+            crawl_prefixes: Vec::new(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            user_agents: Vec::new(),
+            headers: BTreeMap::new(),
+            cookie: None,
+            proxy: None,
+            browser_domains: Vec::new(),
+            strip_boilerplate: false,
+            dedup_content: false,
+            meta_robots: true,
+            assets: false,
+            include_assets: false,
+            prefetch_head: false,
+            checksums: false,
+            save_html: false,
+            toc: false,
+            heading_offset: 0,
+            keep_dead_links: false,
+            collect_images: false,
+            resume: false,
+            diff_previous: false,
+            since: None,
+            skip_undated: false,
+            dry_run: false,
+            sitemaps: Vec::new(),
+            urls: Vec::new(),
+            cross_domain_sitemaps: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            tracking_params: ["utm_*", "gclid", "fbclid", "msclkid"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+}
+
+impl CrawlerConfig {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    // The User-Agent whose robots.txt group is applied: the first of user_agents when
+    // they rotate, as one set of rules covers every request to the site
+    fn robots_user_agent(&self) -> &str {
+        self.user_agents.first().unwrap_or(&self.user_agent)
+    }
+
+    // How many pages to write out at a time during the crawl
+    fn pages_per_flush(&self) -> usize {
+        match self.flush_every {
+            0 => DEFAULT_FLUSH_EVERY,
+            pages => pages,
+        }
+    }
+
+    // Whether written pages' Markdown is dropped from the CrawlResult to bound memory
+    fn drops_flushed_markdown(&self) -> bool {
+        self.flush_every > 0 || self.single_file.is_some() || self.format == OutputFormat::Jsonl
+    }
+
+    fn delay(&self) -> Duration {
+        Duration::from_millis(self.delay_ms.unwrap_or(0))
+    }
+
+    /// The configured extra headers, failing on a name or value HTTP does not allow
+    pub fn request_headers(&self) -> Result<HeaderMap, String> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| format!("Invalid header name: {}", name))?;
+            let value = HeaderValue::from_str(value.trim())
+                .map_err(|_| format!("Invalid value for header {}", name))?;
+            headers.insert(name, value);
+        }
+        Ok(headers)
+    }
+
+    /// The proxy requests go through, from `proxy` or else the HTTPS_PROXY and
+    /// HTTP_PROXY environment variables, failing on a URL that is not a usable proxy
+    pub fn proxy_url(&self) -> Result<Option<String>, String> {
+        let proxy = self.proxy.clone().or_else(|| {
+            ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"]
+                .iter()
+                .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+        });
+        let Some(proxy) = proxy else {
+            return Ok(None);
+        };
+        let url = Url::parse(&proxy).map_err(|e| format!("Invalid proxy URL {}: {}", proxy, e))?;
+        if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+            return Err(format!(
+                "Unsupported proxy scheme {}: in {}, use http, https or socks5",
+                url.scheme(),
+                proxy
+            ));
+        }
+        if url.host_str().is_none_or(str::is_empty) {
+            return Err(format!("Proxy URL {} has no host", proxy));
+        }
+        Ok(Some(proxy))
+    }
+
+    /// Conversion settings for [`html_to_markdown`] taken from this configuration
+    pub fn markdown_options(&self) -> MarkdownOptions {
+        MarkdownOptions {
+            strip_boilerplate: self.strip_boilerplate,
+            plain_text: self.format == OutputFormat::Text,
+            toc: self.toc,
+            heading_offset: self.heading_offset,
+            keep_dead_links: self.keep_dead_links,
+        }
+    }
+}
+
+/// How converted pages are written to the output directory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Markdown,
+    Jsonl,
+    Text,
+    /// Sanitized HTML keeping only the page's structure, in one .html file per page
+    #[serde(rename = "html-clean")]
+    HtmlClean,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "markdown" | "md" => Ok(Self::Markdown),
+            "jsonl" => Ok(Self::Jsonl),
+            "text" | "txt" => Ok(Self::Text),
+            "html-clean" => Ok(Self::HtmlClean),
+            other => Err(format!("unknown output format: {}", other)),
+        }
+    }
+}
+
+// Set by the first Ctrl-C; the crawl winds down and saves what it has
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether Ctrl-C has asked the crawl to stop
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// The first Ctrl-C stops the crawl gracefully; a second one exits immediately
+pub fn install_interrupt_handler() {
+    let installed = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted, finishing in-flight pages (press Ctrl-C again to quit now)");
+    });
+    if let Err(e) = installed {
+        warn!("Could not install Ctrl-C handler: {}", e);
+    }
+}
+
+/// Prefix bare domains like `example.com` with https://
+pub fn normalize_domain(domain: &str) -> String {
+    let domain = domain.trim();
+    if domain.starts_with("http://") || domain.starts_with("https://") {
+        domain.to_string()
+    } else {
+        format!("https://{}", domain)
+    }
+}
+
+/// Crawls domains with one configuration and writes what it finds to the output
+/// directory. Pages from every domain a `Crawler` runs share its fetch limits, so
+/// several can run at once.
+///
+/// ```no_run
+/// use web_crawler::{Crawler, CrawlerConfig};
+///
+/// # async fn example() -> Result<(), web_crawler::CrawlerError> {
+/// let config = CrawlerConfig {
+///     output_dir: "docs".into(),
+///     ..CrawlerConfig::default()
+/// };
+/// let result = Crawler::new(config).run("https://example.com").await?;
+/// println!("Saved {} pages", result.pages.len());
+/// # Ok(())
+/// # }
+/// ```
+pub struct Crawler {
+    config: CrawlerConfig,
+    scheduler: Scheduler,
+    progress: MultiProgress,
+    // Domains crawl in parallel, but saves share the manifest and visited files
+    save_lock: tokio::sync::Mutex<SaveState>,
+}
+
+// What the domains a crawler runs have written so far
+#[derive(Default)]
+struct SaveState {
+    // Whether --single-file has been started, so later domains append to it
+    single_file_started: bool,
+    // Every URL visited.json lists, read from it when the first page is saved
+    visited: Option<BTreeSet<String>>,
+    // Final URLs output.jsonl has a record for, read from it when the first page is
+    // saved to it
+    jsonl_urls: Option<HashSet<String>>,
+}
+
+impl Crawler {
+    pub fn new(config: CrawlerConfig) -> Self {
+        Self {
+            scheduler: Scheduler::new(&config),
+            progress: MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+            save_lock: tokio::sync::Mutex::new(SaveState::default()),
+            config,
+        }
+    }
+
+    /// Draw a progress bar on stderr for each domain being fetched
+    pub fn show_progress(mut self, show: bool) -> Self {
+        if show {
+            self.progress = MultiProgress::new();
+        }
+        self
+    }
+
+    pub fn config(&self) -> &CrawlerConfig {
+        &self.config
+    }
+
+    /// Requests sent so far across every domain this crawler has run, including
+    /// robots.txt, sitemaps and images
+    pub fn requests(&self) -> u64 {
+        self.scheduler.traffic.snapshot().0
+    }
+
+    /// Response bytes downloaded so far across every domain, including the pages the
+    /// native crawl visits to find links
+    pub fn bytes_downloaded(&self) -> u64 {
+        self.scheduler.traffic.snapshot().1
+    }
+
+    /// Crawl one domain and save its pages, unless this is a dry run, which only lists
+    /// the URLs it would fetch in [`CrawlResult::planned`]
+    pub async fn run(&self, domain: &str) -> Result<CrawlResult, CrawlerError> {
+        self.run_with(domain, |_, _| {}).await
+    }
+
+    /// Like [`Crawler::run`], but also hands each page to `on_page` as soon as it has
+    /// been converted, along with the raw HTML it came from. Pages fetched in parallel
+    /// take turns calling it, so it never runs twice at once. Every converted page is
+    /// passed, including ones later skipped as duplicates.
+    ///
+    /// ```no_run
+    /// # async fn example() -> Result<(), web_crawler::CrawlerError> {
+    /// let crawler = web_crawler::Crawler::new(Default::default());
+    /// let mut html_bytes = 0;
+    /// crawler
+    ///     .run_with("https://example.com", |page, html| {
+    ///         println!("{}: {} words", page.url, page.word_count);
+    ///         html_bytes += html.len();
+    ///     })
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn run_with<F>(&self, domain: &str, on_page: F) -> Result<CrawlResult, CrawlerError>
+    where
+        F: FnMut(&PageResult, &str) + Send,
+    {
+        let domain = normalize_domain(domain);
+        // Flushes and the save below update the manifest, so keep the last crawl's copy
+        let previous = self
+            .config
+            .diff_previous
+            .then(|| load_manifest(&self.config.output_dir));
+        let on_page = std::sync::Mutex::new(on_page);
+        let on_page = |page: &PageResult, html: &str| {
+            let mut on_page = on_page.lock().unwrap_or_else(|e| e.into_inner());
+            on_page(page, html)
+        };
+        let result = run_crawler(
+            &domain,
+            &self.config,
+            &self.scheduler,
+            &self.progress,
+            &on_page,
+            &self.save_lock,
+        )
+        .await?;
+        if !self.config.dry_run
+            && let Some(previous) = &previous
+        {
+            // Other domains' flushes update the manifest too
+            let _state = self.save_lock.lock().await;
+            let mut current = load_manifest(&self.config.output_dir);
+            let changes = find_changes(&result, previous, &current);
+            save_changes(&self.config.output_dir, &changes)?;
+            // So the next crawl compares against this one, not ones before it
+            if !changes.removed.is_empty() {
+                current.retain(|url, _| !changes.removed.contains(url));
+                save_manifest(&self.config.output_dir, &current)?;
+            }
+        }
+        Ok(result)
+    }
+}
+
+// Main crawler function
+async fn run_crawler(
+    domain: &str,
+    config: &CrawlerConfig,
+    scheduler: &Scheduler,
+    progress: &MultiProgress,
+    on_page: &(dyn Fn(&PageResult, &str) + Sync),
+    save_lock: &tokio::sync::Mutex<SaveState>,
+) -> Result<CrawlResult, CrawlerError> {
+    let (requests_before, bytes_before) = scheduler.traffic.snapshot();
+    // Normalize domain
+    let domain = domain.trim_end_matches('/').to_string();
+    info!("Starting crawler for domain: {}", domain);
+    if !config.headers.is_empty() || config.cookie.is_some() {
+        // Names only: the values are usually credentials
+        let mut sent: Vec<String> = config
+            .headers
+            .keys()
+            .map(|name| format!("{}: <redacted>", name))
+            .collect();
+        if config.cookie.is_some() {
+            sent.push("Cookie: <redacted>".to_string());
+        }
+        info!("Sending extra headers: {}", sent.join(", "));
+    }
+    let filter = UrlFilter::new(&config.include, &config.exclude)?;
+    // A bad proxy would otherwise only show up as every request failing
+    config.proxy_url().map_err(CrawlerError::Config)?;
+    let head_client = if config.prefetch_head {
+        Some(new_head_client(config)?)
+    } else {
+        None
+    };
+
+    // Pages given with --url-list need no discovery, or robots.txt's permission
+    let mut seen = HashSet::new();
+    let given_urls: Vec<String> = config
+        .urls
+        .iter()
+        .filter(|url| url_host(url) == url_host(&domain))
+        .map(|url| normalize_url(url, &config.tracking_params))
+        .filter(|url| seen.insert(url.clone()))
+        .collect();
+
+    // Step 1: Load robots.txt and get sitemap URLs, unless --sitemap named them
+    let given_sitemaps: Vec<String> = config
+        .sitemaps
+        .iter()
+        .filter(|sitemap| url_host(sitemap) == url_host(&domain))
+        .cloned()
+        .collect();
+    let robots = if !given_urls.is_empty() {
+        info!(
+            "Fetching the {} URLs given with --url-list; skipping robots.txt and sitemaps",
+            given_urls.len()
+        );
+        RobotsTxt::default()
+    } else if given_sitemaps.is_empty() {
+        let mut robots = get_sitemap_urls_from_robots(&domain, config, scheduler).await?;
+        // robots.txt may point anywhere; only trust sitemaps run by the same site
+        if !config.cross_domain_sitemaps {
+            robots.sitemaps.retain(|sitemap| {
+                let same_site = same_registrable_domain(sitemap, &domain);
+                if !same_site {
+                    warn!(
+                        "Skipping sitemap {} from robots.txt: it is not on {}'s domain",
+                        sitemap, domain
+                    );
+                }
+                same_site
+            });
+        }
+        robots
+    } else {
+        info!("Using sitemaps given with --sitemap; skipping robots.txt");
+        RobotsTxt {
+            sitemaps: given_sitemaps,
+            ..RobotsTxt::default()
+        }
+    };
+    // Wait between requests as robots.txt asks, unless --delay asks for longer
+    let delay = robots.crawl_delay.unwrap_or_default().max(config.delay());
+    info!("Delay between requests: {:?}", delay);
+    scheduler.set_delay(&domain, delay);
+
+    let sitemap_urls = &robots.sitemaps;
+    info!(
+        "Found {} sitemap URLs: {:?}",
+        sitemap_urls.len(),
+        sitemap_urls
+    );
+
+    // Step 2: Get all page URLs
+    let mut lastmods = HashMap::new();
+    let mut images = Vec::new();
+    let page_urls = if !given_urls.is_empty() {
+        given_urls
+    } else if !sitemap_urls.is_empty() {
+        // Load sitemaps recursively
+        let sitemap = get_all_page_urls_from_sitemaps(sitemap_urls, config, scheduler).await?;
+        info!("Collected {} URLs from sitemaps", sitemap.pages.len());
+        lastmods = sitemap.lastmods;
+        images = sitemap.images;
+        sitemap.pages
+    } else {
+        // Step 3: Try direct sitemap.xml if no robots.txt
+        let sitemap_url = format!("{}/sitemap.xml", domain);
+        info!("No sitemap URLs in robots.txt, trying: {}", sitemap_url);
+        let sitemap_urls = vec![sitemap_url.clone()];
+        let sitemap = get_all_page_urls_from_sitemaps(&sitemap_urls, config, scheduler).await?;
+
+        if !sitemap.pages.is_empty() {
+            info!("Collected {} URLs from sitemap.xml", sitemap.pages.len());
+            lastmods = sitemap.lastmods;
+            images = sitemap.images;
+            sitemap.pages
+        } else {
+            let feed_urls = get_page_urls_from_feeds(&domain, config, scheduler).await;
+            if !feed_urls.is_empty() {
+                info!("Collected {} URLs from feeds", feed_urls.len());
+                feed_urls
+            } else {
+                // Step 4: Fallback to native crawl
+                info!("No sitemap or feed pages found, falling back to native crawl");
+                // Existing users rely on the native crawl pausing by default
+                let native_delay = match config.delay_ms {
+                    Some(_) => delay,
+                    None => delay.max(Duration::from_millis(NATIVE_CRAWL_DELAY_MS)),
+                };
+                native_crawl(&domain, config, scheduler, native_delay).await?
+            }
+        }
+    };
+
+    // What the site still has, for diff_previous to tell removed pages from skipped ones
+    let collected_urls = page_urls.clone();
+    // Taken before any are skipped, so a page's file stays put between runs
+    let parent_pages = if config.preserve_structure {
+        parent_pages(&page_urls)
+    } else {
+        HashSet::new()
+    };
+
+    // Drop pages the site asked crawlers to avoid
+    let collected = page_urls.len();
+    let page_urls: Vec<String> = page_urls
+        .into_iter()
+        .filter(|url| {
+            let allowed = robots.is_allowed(url);
+            if !allowed {
+                debug!("Skipping URL disallowed by robots.txt: {}", url);
+            }
+            allowed
+        })
+        .collect();
+    if page_urls.len() < collected {
+        info!(
+            "Skipped {} URLs disallowed by robots.txt",
+            collected - page_urls.len()
+        );
+    }
+
+    // Keep only the paths selected by --include/--exclude
+    let collected = page_urls.len();
+    let mut page_urls: Vec<String> = page_urls
+        .into_iter()
+        .filter(|url| {
+            let selected = filter.is_selected(url);
+            if !selected {
+                debug!("Skipping URL filtered out by include/exclude: {}", url);
+            }
+            selected
+        })
+        .collect();
+    if page_urls.len() < collected {
+        info!(
+            "Skipped {} URLs filtered out by include/exclude patterns",
+            collected - page_urls.len()
+        );
+    }
+
+    // Keep only pages the sitemap says changed on or after --since
+    if let Some(since) = config.since {
+        let collected = page_urls.len();
+        page_urls.retain(|url| {
+            let selected = match lastmods.get(url).and_then(|lastmod| lastmod_date(lastmod)) {
+                Some(date) => date >= since,
+                None => !config.skip_undated,
+            };
+            if !selected {
+                debug!("Skipping URL not modified since {}: {}", since, url);
+            }
+            selected
+        });
+        if page_urls.len() < collected {
+            info!(
+                "Skipped {} URLs not modified since {}",
+                collected - page_urls.len(),
+                since
+            );
+        }
+    }
+
+    // Files such as PDFs and images cannot be converted, so only fetch them to keep them
+    if !config.include_assets {
+        let collected = page_urls.len();
+        page_urls.retain(|url| {
+            let skipped = has_non_html_extension(url);
+            if skipped {
+                debug!("Skipping non-HTML URL: {}", url);
+            }
+            !skipped
+        });
+        if page_urls.len() < collected {
+            info!(
+                "Skipped {} URLs with non-HTML file extensions",
+                collected - page_urls.len()
+            );
+        }
+    }
+
+    // Skip pages whose sitemap lastmod is unchanged since the previous run
+    let manifest = load_manifest(&config.output_dir);
+    let collected = page_urls.len();
+    let mut page_urls: Vec<String> = page_urls
+        .into_iter()
+        .filter(|url| {
+            let unchanged = lastmods.get(url).is_some_and(|lastmod| {
+                manifest
+                    .get(url)
+                    .is_some_and(|entry| entry.lastmod.as_ref() == Some(lastmod))
+            });
+            if unchanged {
+                debug!("Skipping unchanged URL: {}", url);
+            }
+            !unchanged
+        })
+        .collect();
+    if page_urls.len() < collected {
+        info!(
+            "Skipped {} URLs unchanged since the last run",
+            collected - page_urls.len()
+        );
+    }
+
+    // Skip pages an earlier run already saved
+    if config.resume {
+        let visited = load_visited(&config.output_dir);
+        let collected = page_urls.len();
+        page_urls.retain(|url| !visited.contains(url));
+        if page_urls.len() < collected {
+            info!(
+                "Resuming: skipped {} URLs saved by a previous run",
+                collected - page_urls.len()
+            );
+        }
+    }
+
+    // Cap the crawl at the configured number of pages
+    if let Some(max_pages) = config.max_pages
+        && page_urls.len() > max_pages
+    {
+        info!(
+            "Dropped {} URLs beyond the limit of {} pages",
+            page_urls.len() - max_pages,
+            max_pages
+        );
+        page_urls.truncate(max_pages);
+    }
+
+    info!("Total URLs to process: {}", page_urls.len());
+    if !config.dry_run && !config.resume {
+        // This domain's entries from earlier runs are stale; other domains' are kept
+        let mut state = save_lock.lock().await;
+        let visited = state
+            .visited
+            .get_or_insert_with(|| load_visited(&config.output_dir));
+        forget_visited(visited, &domain);
+    }
+    if config.dry_run {
+        return Ok(CrawlResult {
+            domain,
+            collected: collected_urls,
+            planned: page_urls,
+            ..CrawlResult::default()
+        });
+    }
+    if page_urls.is_empty() {
+        warn!("No URLs collected; no Markdown files will be generated");
+    }
+
+    // Step 5-7: Load HTML and convert to Markdown on a bounded pool of workers
+    let total = page_urls.len();
+    let bar = progress.add(ProgressBar::new(total as u64));
+    bar.set_style(
+        ProgressStyle::with_template("{prefix} [{bar:30}] {pos}/{len} pages, {per_sec}, ETA {eta}")
+            .expect("progress template is valid")
+            .progress_chars("=> "),
+    );
+    bar.set_prefix(domain.clone());
+    // None for a URL that turned out not to be a page
+    let mut outcomes = pin!(
+        stream::iter(page_urls.iter().enumerate())
+            // After Ctrl-C, let in-flight pages finish but start no new ones
+            .take_while(|_| future::ready(!interrupted()))
+            .map(|(i, url)| {
+                let (lastmods, domain, bar, head_client, parent_pages) =
+                    (&lastmods, &domain, &bar, &head_client, &parent_pages);
+                async move {
+                    // Held until the page is done; load_html gives it up while it waits to retry
+                    let mut slot = Some(scheduler.acquire(url).await);
+                    debug!("Processing URL {}/{}: {}", i + 1, total, url);
+                    let head = match head_client {
+                        Some(client) => head_check(client, url, config, scheduler).await,
+                        None => HeadCheck::Fetch,
+                    };
+                    let outcome = match head {
+                        HeadCheck::NotHtml(content_type) => {
+                            info!("Skipping {}: HEAD says {} is not HTML", url, content_type);
+                            None
+                        }
+                        HeadCheck::TooLarge(length) => {
+                            info!(
+                                "Skipping {}: HEAD says its {} bytes exceed max_convert_bytes ({})",
+                                url, length, config.max_convert_bytes
+                            );
+                            Some(Err(PageFailure {
+                                url: url.clone(),
+                                error: format!(
+                                    "response of {} bytes exceeds max_convert_bytes ({})",
+                                    length, config.max_convert_bytes
+                                ),
+                                status: None,
+                            }))
+                        }
+                        HeadCheck::Fetch => match load_html(url, domain, config, scheduler, &mut slot)
+                            .await
+                        {
+                            Ok(FetchedPage { noindex: true, .. }) if config.meta_robots => {
+                                info!("Skipping {}: its meta robots tag says noindex", url);
+                                None
+                            }
+                            Ok(FetchedPage {
+                                html,
+                                final_url,
+                                canonical,
+                                ..
+                            }) => {
+                                let mut markdown = match config.format {
+                                    OutputFormat::HtmlClean => {
+                                        html_to_clean_html(&html, config.markdown_options())
+                                    }
+                                    _ => html_to_markdown(&html, config.markdown_options()),
+                                };
+                                // Plain text and HTML have no Markdown image links to rewrite
+                                if config.assets
+                                    && !matches!(
+                                        config.format,
+                                        OutputFormat::Text | OutputFormat::HtmlClean
+                                    )
+                                {
+                                    let saved_as = markdown_path(
+                                        canonical.as_deref().unwrap_or(&final_url),
+                                        config,
+                                        parent_pages,
+                                    );
+                                    markdown = localize_images(
+                                        &markdown, &final_url, &saved_as, config, scheduler,
+                                    )
+                                    .await;
+                                }
+                                debug!("Converted {} to Markdown", url);
+                                let stripped;
+                                let text = match config.format {
+                                    OutputFormat::HtmlClean => {
+                                        stripped = strip_tags(&markdown);
+                                        &stripped
+                                    }
+                                    _ => &markdown,
+                                };
+                                if !get_fetch_mode(domain, config) && looks_client_rendered(&html, text)
+                                {
+                                    warn!(
+                                        "{} looks client-rendered but browser rendering is not \
+                                         enabled in this build; keeping the HTTP response",
+                                        url
+                                    );
+                                }
+                                let word_count = count_words(text);
+                                // Fetched fine but nearly nothing converted: likely an SPA
+                                let chars = text.trim().chars().count();
+                                let suspicious_empty = chars < config.min_content_chars;
+                                if suspicious_empty {
+                                    warn!(
+                                        "{} converted to only {} characters; its content may be \
+                                         rendered by scripts or in tags that are not converted",
+                                        url, chars
+                                    );
+                                }
+                                let page = PageResult {
+                                    url: url.clone(),
+                                    links: extract_links(
+                                        &html,
+                                        &final_url,
+                                        &config.tracking_params,
+                                    ),
+                                    final_url: canonical.unwrap_or(final_url),
+                                    title: extract_title(&html),
+                                    markdown,
+                                    crawled_at: Utc::now(),
+                                    bytes: html.len(),
+                                    lastmod: lastmods.get(url).cloned(),
+                                    word_count,
+                                    reading_minutes: word_count
+                                        .div_ceil(config.words_per_minute.max(1)),
+                                    suspicious_empty,
+                                };
+                                on_page(&page, &html);
+                                // Saved once the page is known not to be a duplicate
+                                Some(Ok((page, config.save_html.then_some(html))))
+                            }
+                            Err(CrawlerError::NotHtml { content_type, body })
+                                if config.include_assets =>
+                            {
+                                match save_asset(url, &body, &config.output_dir) {
+                                    Ok(()) => {
+                                        info!(
+                                            "Saved {} ({}) without converting it",
+                                            url, content_type
+                                        );
+                                        None
+                                    }
+                                    Err(e) => {
+                                        warn!("Failed to save {}: {}", url, e);
+                                        Some(Err(PageFailure {
+                                            url: url.clone(),
+                                            error: e.to_string(),
+                                            status: None,
+                                        }))
+                                    }
+                                }
+                            }
+                            Err(CrawlerError::NotHtml { content_type, .. }) => {
+                                info!("Skipping {}: {} is not HTML", url, content_type);
+                                None
+                            }
+                            Err(e) => {
+                                warn!("Failed to load HTML for {}: {}", url, e);
+                                let status = match e {
+                                    CrawlerError::Status { status, .. } => Some(status),
+                                    _ => None,
+                                };
+                                Some(Err(PageFailure {
+                                    url: url.clone(),
+                                    error: e.to_string(),
+                                    status,
+                                }))
+                            }
+                        },
+                    };
+                    bar.inc(1);
+                    (i, outcome)
+                }
+            })
+            .buffer_unordered(config.concurrency.max(1))
+    );
+    let mut result = CrawlResult {
+        domain: domain.clone(),
+        images,
+        parent_pages: parent_pages.clone(),
+        collected: collected_urls,
+        ..CrawlResult::default()
+    };
+    // Kept up to date as pages are saved, so a crash leaves the report of what was
+    let mut report = CrawlReport {
+        domain: domain.clone(),
+        ..CrawlReport::default()
+    };
+    // Workers finish out of order; take pages in the order their URLs were collected
+    let mut finished = BTreeMap::new();
+    let mut next = 0;
+    // Several collected URLs can redirect to, or name as canonical, one page; keep the first
+    let mut final_urls = HashSet::new();
+    // With --dedup-content, the first URL seen with each Markdown body
+    let mut contents: HashMap<u64, String> = HashMap::new();
+    while let Some((i, outcome)) = outcomes.next().await {
+        finished.insert(i, outcome);
+        while let Some(outcome) = finished.remove(&next) {
+            next += 1;
+            let (page, html) = match outcome {
+                None => continue,
+                Some(Err(failure)) => {
+                    report.add(&failure.url, 0, Some(failure.error.clone()));
+                    result.failures.push(failure);
+                    continue;
+                }
+                Some(Ok(page)) => page,
+            };
+            if !final_urls.insert(page.final_url.clone()) {
+                info!(
+                    "Skipping {}: it resolves to {}, which was already fetched",
+                    page.url, page.final_url
+                );
+                continue;
+            }
+            if config.dedup_content {
+                match contents.entry(content_hash(&page.markdown)) {
+                    Entry::Occupied(first) => {
+                        info!(
+                            "Skipping {}: its content is identical to {}",
+                            page.url,
+                            first.get()
+                        );
+                        continue;
+                    }
+                    Entry::Vacant(slot) => {
+                        slot.insert(page.url.clone());
+                    }
+                }
+            }
+            if let Some(html) = html
+                && let Err(e) = save_raw_html(
+                    &markdown_path(&page.final_url, config, &parent_pages),
+                    &html,
+                    config,
+                )
+            {
+                warn!("Failed to save the HTML of {}: {}", page.url, e);
+            }
+            result.pages.push(page);
+        }
+        if result.pages.len() - result.flushed >= config.pages_per_flush() {
+            flush_pages(
+                &mut result,
+                config,
+                &mut *save_lock.lock().await,
+                &mut report,
+            )?;
+        }
+    }
+    bar.finish_and_clear();
+    result.interrupted = next < total;
+    result.unprocessed = total - next;
+    let (requests, bytes) = scheduler.traffic.snapshot();
+    result.requests = requests - requests_before;
+    result.bytes_downloaded = bytes - bytes_before;
+    info!(
+        "Made {} requests and downloaded {} bytes for {}",
+        result.requests, result.bytes_downloaded, domain
+    );
+    // Step 8: Save the pages left over from the last flush, or an interrupted crawl's
+    save_results(&mut result, config, &mut *save_lock.lock().await, report)?;
+    Ok(result)
+}
+
+// Fast fingerprint of a page's Markdown for spotting duplicate content
+fn content_hash(markdown: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    markdown.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Bounds page fetches in flight overall and per host, and spaces out each
+// host's requests by its own delay so domains crawled together stay polite
+struct Scheduler {
+    global: Semaphore,
+    per_host: usize,
+    // For hosts no robots.txt has set a delay for, such as image CDNs
+    default_delay: Duration,
+    // Consecutive failed requests that open a host's circuit breaker; 0 never does
+    breaker_failures: u32,
+    breaker_cooldown: Duration,
+    jitter: Jitter,
+    traffic: Traffic,
+    // Requests sent so far, which picks each one's turn in the user_agents rotation
+    user_agent_turn: AtomicUsize,
+    hosts: std::sync::Mutex<HashMap<String, Arc<HostQueue>>>,
+}
+
+// Requests sent and response bytes received, across every host
+#[derive(Default)]
+struct Traffic {
+    requests: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Traffic {
+    fn record(&self, requests: usize, bytes: usize) {
+        self.requests.fetch_add(requests as u64, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> (u64, u64) {
+        (
+            self.requests.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+struct HostQueue {
+    slots: Arc<Semaphore>,
+    limiter: RateLimiter,
+    breaker: std::sync::Mutex<Breaker>,
+}
+
+// A host's run of failed requests; while open, its URLs are skipped rather than fetched
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+// Held for the duration of one page fetch
+struct FetchSlot<'a> {
+    _host: OwnedSemaphorePermit,
+    _global: SemaphorePermit<'a>,
+}
+
+impl Scheduler {
+    fn new(config: &CrawlerConfig) -> Self {
+        Self {
+            global: Semaphore::new(config.concurrency.max(1)),
+            per_host: config.per_host.max(1),
+            default_delay: config.delay(),
+            breaker_failures: config.breaker_failures,
+            breaker_cooldown: Duration::from_secs(config.breaker_cooldown_secs),
+            jitter: Jitter {
+                percent: config.jitter_percent.min(100),
+                rng: std::sync::Mutex::new(match config.jitter_seed {
+                    Some(seed) => fastrand::Rng::with_seed(seed),
+                    None => fastrand::Rng::new(),
+                }),
+            },
+            traffic: Traffic::default(),
+            user_agent_turn: AtomicUsize::new(0),
+            hosts: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Use `delay` between requests to the host of `url`, e.g. its robots.txt Crawl-delay
+    fn set_delay(&self, url: &str, delay: Duration) {
+        let queue = self.new_queue(delay);
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        hosts.insert(url_host(url), queue);
+    }
+
+    fn host(&self, url: &str) -> Arc<HostQueue> {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        hosts
+            .entry(url_host(url))
+            .or_insert_with(|| self.new_queue(self.default_delay))
+            .clone()
+    }
+
+    fn new_queue(&self, delay: Duration) -> Arc<HostQueue> {
+        Arc::new(HostQueue {
+            slots: Arc::new(Semaphore::new(self.per_host)),
+            limiter: RateLimiter::new(delay),
+            breaker: std::sync::Mutex::new(Breaker::default()),
+        })
+    }
+
+    // The User-Agent for the next request, cycling through user_agents when there
+    // are several
+    fn next_user_agent<'a>(&self, config: &'a CrawlerConfig) -> &'a str {
+        match config.user_agents.as_slice() {
+            [] => &config.user_agent,
+            [only] => only,
+            pool => &pool[self.user_agent_turn.fetch_add(1, Ordering::Relaxed) % pool.len()],
+        }
+    }
+
+    // Wait for a free slot on the URL's host, then a global slot
+    async fn acquire(&self, url: &str) -> FetchSlot<'_> {
+        let queue = self.host(url);
+        let host = queue
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("scheduler semaphores are never closed");
+        let global = self
+            .global
+            .acquire()
+            .await
+            .expect("scheduler semaphores are never closed");
+        FetchSlot {
+            _host: host,
+            _global: global,
+        }
+    }
+
+    // Give up a page's fetch slot while it waits `wait` to retry, so other pages are
+    // fetched meanwhile, then queue for a slot again
+    async fn wait_for_retry<'a>(
+        &'a self,
+        slot: &mut Option<FetchSlot<'a>>,
+        url: &str,
+        wait: Duration,
+    ) {
+        slot.take();
+        tokio::time::sleep(wait).await;
+        *slot = Some(self.acquire(url).await);
+    }
+
+    // Wait out the delay of the URL's host before sending it a request
+    async fn pace(&self, url: &str) {
+        self.host(url).limiter.wait(&self.jitter).await;
+    }
+
+    // How much longer the URL's host is being skipped for, if its breaker is open.
+    // Once the cooldown is over requests go through again, and the next one decides.
+    fn breaker_open(&self, url: &str) -> Option<Duration> {
+        let queue = self.host(url);
+        let breaker = queue.breaker.lock().unwrap_or_else(|e| e.into_inner());
+        let remaining = breaker.open_until?.checked_duration_since(Instant::now())?;
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    // Count a request to the URL's host as answered or failed. Enough failures in a
+    // row open its breaker; the first answer after that closes it.
+    fn record_request(&self, url: &str, answered: bool) {
+        let queue = self.host(url);
+        let mut breaker = queue.breaker.lock().unwrap_or_else(|e| e.into_inner());
+        if answered {
+            if breaker.open_until.take().is_some() {
+                info!(
+                    "{} is answering again; closed its circuit breaker",
+                    url_host(url)
+                );
+            }
+            breaker.failures = 0;
+            return;
+        }
+        breaker.failures += 1;
+        if self.breaker_failures > 0 && breaker.failures >= self.breaker_failures {
+            if breaker
+                .open_until
+                .is_none_or(|until| until <= Instant::now())
+            {
+                warn!(
+                    "{} failed {} requests in a row; skipping its URLs for {:?}",
+                    url_host(url),
+                    breaker.failures,
+                    self.breaker_cooldown
+                );
+            }
+            breaker.open_until = Some(Instant::now() + self.breaker_cooldown);
+        }
+    }
+}
+
+// Lowercased host and port of a URL, which keys the per-host queues
+fn url_host(url: &str) -> String {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    without_scheme
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+// Spaces out request starts across all workers by a fixed delay
+struct RateLimiter {
+    delay: Duration,
+    next_slot: tokio::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            next_slot: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn wait(&self, jitter: &Jitter) {
+        if self.delay.is_zero() {
+            return;
+        }
+        // Holding the lock while sleeping queues the workers up behind each other
+        let mut next_slot = self.next_slot.lock().await;
+        tokio::time::sleep_until((*next_slot).into()).await;
+        *next_slot = Instant::now() + jitter.apply(self.delay);
+    }
+}
+
+// Random spread of up to `percent` either side of each delay, from a seedable generator
+struct Jitter {
+    percent: u32,
+    rng: std::sync::Mutex<fastrand::Rng>,
+}
+
+impl Jitter {
+    fn apply(&self, delay: Duration) -> Duration {
+        if self.percent == 0 {
+            return delay;
+        }
+        let unit = self.rng.lock().unwrap_or_else(|e| e.into_inner()).f64() * 2.0 - 1.0;
+        delay.mul_f64(1.0 + unit * f64::from(self.percent) / 100.0)
+    }
+}
+
+// Include/exclude glob patterns applied to the path of each collected URL
+struct UrlFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl UrlFilter {
+    fn new(include: &[String], exclude: &[String]) -> Result<Self, globset::Error> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(build_glob_set(include)?)
+        };
+        Ok(Self {
+            include,
+            exclude: build_glob_set(exclude)?,
+        })
+    }
+
+    fn is_selected(&self, url: &str) -> bool {
+        let path = url_path(url).split('?').next().unwrap_or_default();
+        let included = self.include.as_ref().is_none_or(|set| set.is_match(path));
+        included && !self.exclude.is_match(path)
+    }
+}
+
+// Compile patterns into one matcher; an empty list matches nothing
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+/// Everything produced by one crawl of a domain
+#[derive(Debug, Default)]
+pub struct CrawlResult {
+    pub domain: String,
+    pub pages: Vec<PageResult>,
+    pub failures: Vec<PageFailure>,
+    /// Set when Ctrl-C stopped the crawl before every URL was fetched
+    pub interrupted: bool,
+    pub unprocessed: usize,
+    /// Image URLs from image sitemap entries, collected with `collect_images`
+    pub images: Vec<String>,
+    /// How many leading pages have been written out. With `flush_every`, JSONL or
+    /// `single_file` output their Markdown has been dropped to free the memory.
+    pub flushed: usize,
+    /// Requests sent while the domain was crawled, robots.txt and sitemaps included.
+    /// Domains crawled at the same time count each other's requests too.
+    pub requests: u64,
+    /// Response bytes downloaded while the domain was crawled, counted like `requests`
+    pub bytes_downloaded: u64,
+    /// Collected URLs that other collected pages sit below, which `preserve_structure`
+    /// saves as the index.md of their own directory
+    pub parent_pages: HashSet<String>,
+    /// Every page URL found for the domain, including those robots.txt, the filters,
+    /// `since`, `resume` or `max_pages` then skipped
+    pub collected: Vec<String>,
+    /// With `dry_run`, the URLs that would have been fetched, in crawl order
+    pub planned: Vec<String>,
+}
+
+/// A page that was fetched and converted
+#[derive(Debug, Clone)]
+pub struct PageResult {
+    /// The URL as collected, which the manifest and visited set are keyed by
+    pub url: String,
+    /// Where redirects ended up, or the page's same-site rel=canonical URL; names the
+    /// output file
+    pub final_url: String,
+    pub title: Option<String>,
+    /// Same-host link targets on the page, normalized
+    pub links: Vec<String>,
+    /// Converted body, without frontmatter
+    pub markdown: String,
+    pub crawled_at: DateTime<Utc>,
+    /// Size of the fetched HTML
+    pub bytes: usize,
+    pub lastmod: Option<String>,
+    /// Words of text in the Markdown, leaving out its syntax and link targets
+    pub word_count: usize,
+    /// Estimated at words_per_minute, rounded up
+    pub reading_minutes: usize,
+    /// The converted text came out shorter than min_content_chars
+    pub suspicious_empty: bool,
+}
+
+/// A page that could not be fetched, with the last error
+#[derive(Debug, Clone)]
+pub struct PageFailure {
+    pub url: String,
+    pub error: String,
+    /// The HTTP error status the page answered with, if it got that far
+    pub status: Option<u16>,
+}
+
+// Write the pages not yet flushed, then what the crawl found out about the domain
+// as a whole: its image URLs, broken links and final report
+fn save_results(
+    result: &mut CrawlResult,
+    config: &CrawlerConfig,
+    state: &mut SaveState,
+    mut report: CrawlReport,
+) -> Result<(), CrawlerError> {
+    report.interrupted = result.interrupted;
+    report.requests = result.requests;
+    report.bytes_downloaded = result.bytes_downloaded;
+    if config.collect_images && !result.images.is_empty() {
+        save_images(&config.output_dir, &result.images)?;
+    }
+    save_broken_links(
+        &config.output_dir,
+        &result.domain,
+        find_broken_links(result),
+    )?;
+    flush_pages(result, config, state, &mut report)
+}
+
+// Write the pages converted since the last flush, and in the same step mark them
+// visited and add them to the report, so a crash leaves --resume a record of every
+// page on disk. With `flush_every` or output that is appended to, their Markdown is
+// dropped to free the memory.
+fn flush_pages(
+    result: &mut CrawlResult,
+    config: &CrawlerConfig,
+    state: &mut SaveState,
+    report: &mut CrawlReport,
+) -> Result<(), CrawlerError> {
+    let pages = &mut result.pages[result.flushed..];
+    if let Some(path) = &config.single_file {
+        save_single_file(path, pages, state.single_file_started)?;
+        state.single_file_started = true;
+    }
+    let visited = state
+        .visited
+        .get_or_insert_with(|| load_visited(&config.output_dir));
+    let mut writer = PageWriter::open(config, &result.parent_pages, &mut state.jsonl_urls)?;
+    for page in pages.iter_mut() {
+        if let Err(e) = writer.write(page, config) {
+            error!("Failed to save Markdown for {}: {}", page.url, e);
+            report.add(&page.url, page.bytes, Some(format!("save failed: {}", e)));
+            continue;
+        }
+        report.add_saved(page);
+        if page.suspicious_empty {
+            report.suspicious_empty.push(page.url.clone());
+        }
+        visited.insert(page.url.clone());
+        if config.drops_flushed_markdown() {
+            page.markdown = String::new();
+        }
+    }
+    writer.finish(config)?;
+    save_visited(&config.output_dir, visited)?;
+    save_report(&config.output_dir, report)?;
+    debug!("Flushed {} pages", pages.len());
+    result.flushed = result.pages.len();
+    Ok(())
+}
+
+// Writes pages in the configured format, leaving ones whose content the manifest
+// already has alone, and records each page written in the manifest
+struct PageWriter<'a> {
+    parent_pages: &'a HashSet<String>,
+    manifest: Manifest,
+    manifest_changed: bool,
+    // output.jsonl, and the final URLs it already has a record for
+    jsonl: Option<(BufWriter<File>, &'a mut HashSet<String>)>,
+    unchanged: usize,
+}
+
+impl<'a> PageWriter<'a> {
+    fn open(
+        config: &CrawlerConfig,
+        parent_pages: &'a HashSet<String>,
+        jsonl_urls: &'a mut Option<HashSet<String>>,
+    ) -> Result<Self, CrawlerError> {
+        let jsonl = match config.format {
+            _ if config.single_file.is_some() => None,
+            OutputFormat::Markdown | OutputFormat::Text | OutputFormat::HtmlClean => None,
+            OutputFormat::Jsonl => Some((
+                open_jsonl(&config.output_dir)?,
+                jsonl_urls.get_or_insert_with(|| load_jsonl_urls(&config.output_dir)),
+            )),
+        };
+        Ok(Self {
+            parent_pages,
+            manifest: load_manifest(&config.output_dir),
+            manifest_changed: false,
+            jsonl,
+            unchanged: 0,
+        })
+    }
+
+    // --single-file pages are written by save_single_file; this only records them
+    fn write(&mut self, page: &PageResult, config: &CrawlerConfig) -> Result<(), CrawlerError> {
+        let entry = ManifestEntry {
+            lastmod: page.lastmod.clone(),
+            content_hash: Some(sha256_hex(page.markdown.as_bytes())),
+        };
+        // Leave an unchanged page's file, and its mtime, alone, unless it has gone missing
+        // or output.jsonl no longer has its record
+        let previous_hash = self
+            .manifest
+            .get(&page.url)
+            .and_then(|previous| previous.content_hash.as_ref());
+        let saved_as = markdown_path(&page.final_url, config, self.parent_pages);
+        let is_unchanged = config.single_file.is_none()
+            && previous_hash == entry.content_hash.as_ref()
+            && match &self.jsonl {
+                Some((_, saved)) => saved.contains(&page.final_url),
+                None => config.output_dir.join(&saved_as).exists(),
+            };
+        match &mut self.jsonl {
+            _ if config.single_file.is_some() || is_unchanged => {}
+            Some((writer, saved)) => {
+                write_jsonl_record(writer, page)?;
+                saved.insert(page.final_url.clone());
+            }
+            None if config.format == OutputFormat::HtmlClean => {
+                save_markdown(&saved_as, &render_clean_html(page), config)?
+            }
+            None if config.format == OutputFormat::Text => {
+                save_markdown(&saved_as, &render_text(page), config)?
+            }
+            None => save_markdown(&saved_as, &render_page(page), config)?,
+        }
+        if is_unchanged {
+            debug!("Content of {} is unchanged; not rewriting it", page.url);
+            self.unchanged += 1;
+        } else {
+            debug!(
+                "Saved Markdown for {} ({} bytes of HTML)",
+                page.url, page.bytes
+            );
+        }
+        if self.manifest.get(&page.url) != Some(&entry) {
+            self.manifest.insert(page.url.clone(), entry);
+            self.manifest_changed = true;
+        }
+        Ok(())
+    }
+
+    fn finish(self, config: &CrawlerConfig) -> Result<(), CrawlerError> {
+        if self.unchanged > 0 {
+            info!(
+                "Left {} pages with unchanged content as they were",
+                self.unchanged
+            );
+        }
+        if let Some((mut writer, _)) = self.jsonl {
+            writer.flush()?;
+        }
+        if self.manifest_changed {
+            save_manifest(&config.output_dir, &self.manifest)?;
+        }
+        Ok(())
+    }
+}
+
+const REPORT_FILE: &str = "report.json";
+
+// Machine-readable summary of one crawl, written next to the Markdown files
+#[derive(Debug, Default, Serialize)]
+struct CrawlReport {
+    domain: String,
+    interrupted: bool,
+    fetched: usize,
+    failed: usize,
+    total_bytes: usize,
+    // Every request made for the domain, robots.txt and sitemaps included, and the
+    // response bytes they downloaded
+    requests: u64,
+    bytes_downloaded: u64,
+    // Pages that converted to almost no text
+    suspicious_empty: Vec<String>,
+    pages: Vec<PageReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct PageReport {
+    url: String,
+    status: PageStatus,
+    bytes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    word_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reading_minutes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum PageStatus {
+    Fetched,
+    Failed,
+}
+
+impl CrawlReport {
+    fn add(&mut self, url: &str, bytes: usize, error: Option<String>) {
+        let status = if error.is_some() {
+            self.failed += 1;
+            PageStatus::Failed
+        } else {
+            self.fetched += 1;
+            PageStatus::Fetched
+        };
+        self.total_bytes += bytes;
+        self.pages.push(PageReport {
+            url: url.to_string(),
+            status,
+            bytes,
+            word_count: None,
+            reading_minutes: None,
+            error,
+        });
+    }
+
+    // A saved page, with its reading statistics
+    fn add_saved(&mut self, page: &PageResult) {
+        self.add(&page.url, page.bytes, None);
+        if let Some(entry) = self.pages.last_mut() {
+            entry.word_count = Some(page.word_count);
+            entry.reading_minutes = Some(page.reading_minutes);
+        }
+    }
+}
+
+// Write the report sorted by URL, so reports from two runs diff cleanly. report.json
+// holds one per domain, keyed by it.
+fn save_report(output_dir: &Path, report: &mut CrawlReport) -> Result<(), CrawlerError> {
+    report.pages.sort_by(|a, b| a.url.cmp(&b.url));
+    report.suspicious_empty.sort();
+    create_dir_all(output_dir)?;
+    let path = output_dir.join(REPORT_FILE);
+    save_domain_entry(&path, &report.domain, report)?;
+    info!(
+        "Wrote crawl report to {} ({} fetched, {} failed)",
+        path.display(),
+        report.fetched,
+        report.failed
+    );
+    Ok(())
+}
+
+// Set `domain`'s entry in a JSON object of per-domain entries, keeping the ones other
+// domains saved to the same directory. Each entry names its domain; anything else in
+// the file, such as a single domain's entry from an older version, is dropped.
+fn save_domain_entry<T: Serialize>(
+    path: &Path,
+    domain: &str,
+    entry: &T,
+) -> Result<(), CrawlerError> {
+    let mut entries: BTreeMap<String, serde_json::Value> = fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    entries.retain(|key, entry| entry.get("domain").and_then(|d| d.as_str()) == Some(key));
+    entries.insert(domain.to_string(), serde_json::to_value(entry)?);
+    write_atomic(path, serde_json::to_string_pretty(&entries)?.as_bytes())
+}
+
+// Page URL -> sitemap lastmod and Markdown hash recorded after each successful save,
+// until --diff-previous finds the page gone
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ManifestEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lastmod: Option<String>,
+    // SHA-256 of the converted Markdown, for sites whose lastmod cannot be trusted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_hash: Option<String>,
+}
+
+type Manifest = BTreeMap<String, ManifestEntry>;
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+// Load the manifest from a previous run; a missing or unreadable file means a full crawl
+fn load_manifest(output_dir: &Path) -> Manifest {
+    let path = output_dir.join(MANIFEST_FILE);
+    let Ok(content) = fs::read_to_string(&path) else {
+        return Manifest::new();
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        warn!("Ignoring malformed manifest {}: {}", path.display(), e);
+        Manifest::new()
+    })
+}
+
+fn save_manifest(output_dir: &Path, manifest: &Manifest) -> Result<(), CrawlerError> {
+    create_dir_all(output_dir)?;
+    let path = output_dir.join(MANIFEST_FILE);
+    write_atomic(&path, serde_json::to_string_pretty(manifest)?.as_bytes())?;
+    debug!(
+        "Saved manifest with {} entries: {}",
+        manifest.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+const BROKEN_LINKS_FILE: &str = "broken_links.json";
+
+// A link target that failed to fetch, and the pages that link to it
+#[derive(Debug, Serialize, Deserialize)]
+struct BrokenLink {
+    url: String,
+    error: String,
+    linked_from: BTreeSet<String>,
+}
+
+// Cross-reference the links found on fetched pages with the URLs that failed
+fn find_broken_links(result: &CrawlResult) -> Vec<BrokenLink> {
+    let failures: HashMap<&str, &str> = result
+        .failures
+        .iter()
+        .map(|failure| (failure.url.as_str(), failure.error.as_str()))
+        .collect();
+    let mut broken: BTreeMap<&str, BrokenLink> = BTreeMap::new();
+    for page in &result.pages {
+        for link in &page.links {
+            if let Some((url, error)) = failures.get_key_value(link.as_str()) {
+                broken
+                    .entry(url)
+                    .or_insert_with(|| BrokenLink {
+                        url: url.to_string(),
+                        error: error.to_string(),
+                        linked_from: BTreeSet::new(),
+                    })
+                    .linked_from
+                    .insert(page.final_url.clone());
+            }
+        }
+    }
+    broken.into_values().collect()
+}
+
+// Replace the domain's broken links in broken_links.json, keeping other domains' and
+// sorting them all by URL
+fn save_broken_links(
+    output_dir: &Path,
+    domain: &str,
+    broken: Vec<BrokenLink>,
+) -> Result<(), CrawlerError> {
+    create_dir_all(output_dir)?;
+    let path = output_dir.join(BROKEN_LINKS_FILE);
+    let host = url_host(domain);
+    let mut listed: Vec<BrokenLink> = fs::read_to_string(&path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    listed.retain(|link| !on_site(&url_host(&link.url), &host, false));
+    let found = broken.len();
+    listed.extend(broken);
+    listed.sort_by(|a, b| a.url.cmp(&b.url));
+    write_atomic(&path, serde_json::to_string_pretty(&listed)?.as_bytes())?;
+    if found > 0 {
+        warn!(
+            "Found {} broken internal links on {}, listed in {}",
+            found,
+            domain,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+const CHANGES_FILE: &str = "changes.json";
+
+// How a domain's pages differ from the last crawl, going by their content hashes
+#[derive(Debug, Serialize)]
+struct CrawlChanges {
+    domain: String,
+    added: BTreeSet<String>,
+    modified: BTreeSet<String>,
+    removed: BTreeSet<String>,
+}
+
+// Compare the manifest before and after this crawl. A page the last crawl saved is
+// removed when the site no longer lists it or now answers 404 or 410 for it; pages
+// skipped this time, by --since or --limit say, or not reached before Ctrl-C are not.
+fn find_changes(result: &CrawlResult, previous: &Manifest, current: &Manifest) -> CrawlChanges {
+    let mut changes = CrawlChanges {
+        domain: result.domain.clone(),
+        added: BTreeSet::new(),
+        modified: BTreeSet::new(),
+        removed: BTreeSet::new(),
+    };
+    for page in &result.pages {
+        // Not in the manifest when saving it failed
+        let Some(entry) = current.get(&page.url) else {
+            continue;
+        };
+        match previous.get(&page.url) {
+            None => {
+                changes.added.insert(page.url.clone());
+            }
+            Some(last) if last.content_hash != entry.content_hash => {
+                changes.modified.insert(page.url.clone());
+            }
+            Some(_) => {}
+        }
+    }
+    let listed: HashSet<&str> = result.collected.iter().map(String::as_str).collect();
+    let gone: HashSet<&str> = result
+        .failures
+        .iter()
+        .filter(|failure| failure.status.is_some_and(is_gone))
+        .map(|failure| failure.url.as_str())
+        .collect();
+    let host = url_host(&result.domain);
+    changes.removed = previous
+        .keys()
+        .filter(|url| url_host(url) == host)
+        .filter(|url| !listed.contains(url.as_str()) || gone.contains(url.as_str()))
+        .cloned()
+        .collect();
+    changes
+}
+
+// changes.json holds each domain's latest changes, keyed by it
+fn save_changes(output_dir: &Path, changes: &CrawlChanges) -> Result<(), CrawlerError> {
+    create_dir_all(output_dir)?;
+    let path = output_dir.join(CHANGES_FILE);
+    save_domain_entry(&path, &changes.domain, changes)?;
+    info!(
+        "Wrote changes since the last crawl to {} ({} added, {} modified, {} removed)",
+        path.display(),
+        changes.added.len(),
+        changes.modified.len(),
+        changes.removed.len()
+    );
+    Ok(())
+}
+
+const VISITED_FILE: &str = "visited.json";
+
+const IMAGES_FILE: &str = "images.txt";
+
+// Add image sitemap URLs to images.txt, one per line and sorted, keeping those earlier
+// runs and other domains listed
+fn save_images(output_dir: &Path, images: &[String]) -> Result<(), CrawlerError> {
+    create_dir_all(output_dir)?;
+    let path = output_dir.join(IMAGES_FILE);
+    let mut listed: BTreeSet<String> = fs::read_to_string(&path)
+        .unwrap_or_default()
+        .lines()
+        .map(String::from)
+        .collect();
+    listed.extend(images.iter().cloned());
+    let mut content = String::new();
+    for image in &listed {
+        content.push_str(image);
+        content.push('\n');
+    }
+    write_atomic(&path, content.as_bytes())?;
+    info!("Listed {} image URLs in {}", listed.len(), path.display());
+    Ok(())
+}
+
+// URLs saved by earlier runs; a missing or unreadable file means nothing was saved
+fn load_visited(output_dir: &Path) -> BTreeSet<String> {
+    let path = output_dir.join(VISITED_FILE);
+    let Ok(content) = fs::read_to_string(&path) else {
+        return BTreeSet::new();
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        warn!("Ignoring malformed visited set {}: {}", path.display(), e);
+        BTreeSet::new()
+    })
+}
+
+// Drop a domain's entries, and its www. twin's, keeping other domains' even when their
+// names start the same way (example.com.evil, example.community)
+fn forget_visited(visited: &mut BTreeSet<String>, domain: &str) {
+    let host = url_host(domain);
+    visited.retain(|url| !on_site(&url_host(url), &host, false));
+}
+
+fn save_visited(output_dir: &Path, visited: &BTreeSet<String>) -> Result<(), CrawlerError> {
+    create_dir_all(output_dir)?;
+    let path = output_dir.join(VISITED_FILE);
+    write_atomic(&path, serde_json::to_string_pretty(visited)?.as_bytes())?;
+    debug!(
+        "Saved visited set with {} entries: {}",
+        visited.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+// Directives from robots.txt that apply to this crawler
+#[derive(Debug, Default)]
+struct RobotsTxt {
+    sitemaps: Vec<String>,
+    allow: Vec<String>,
+    disallow: Vec<String>,
+    crawl_delay: Option<Duration>,
+}
+
+impl RobotsTxt {
+    // A URL is allowed unless its path matches a Disallow pattern and no longer Allow
+    // pattern. As in RFC 9309, the longest match wins and Allow wins a tie, so
+    // `Allow: /docs/public` reopens that part of `Disallow: /docs`.
+    fn is_allowed(&self, url: &str) -> bool {
+        let path = url_path(url);
+        let longest_match = |patterns: &[String]| {
+            patterns
+                .iter()
+                .filter(|pattern| robots_pattern_matches(pattern, path))
+                .map(String::len)
+                .max()
+        };
+        match longest_match(&self.disallow) {
+            Some(disallowed) => {
+                longest_match(&self.allow).is_some_and(|allowed| allowed >= disallowed)
+            }
+            None => true,
+        }
+    }
+}
+
+// Step 1: Load robots.txt and extract sitemap URLs and rules
+async fn get_sitemap_urls_from_robots(
+    domain: &str,
+    config: &CrawlerConfig,
+    scheduler: &Scheduler,
+) -> Result<RobotsTxt, CrawlerError> {
+    let robots_url = format!("{}/robots.txt", domain);
+    debug!("Fetching robots.txt: {}", robots_url);
+    let mut website = new_website(&robots_url, config, scheduler);
+    website.configuration.depth = 0;
+    let mut robots = RobotsTxt::default();
+    if scrape_with_timeout(&mut website, config, scheduler)
+        .await
+        .is_err()
+    {
+        warn!(
+            "Timed out after {:?} fetching {}, continuing without robots.txt",
+            config.timeout(),
+            robots_url
+        );
+        return Ok(robots);
+    }
+
+    if let Some(pages) = website.get_pages() {
+        if let Some(page) = pages.first() {
+            if page.status_code.is_success() {
+                robots = parse_robots_txt(&page.get_html(), config.robots_user_agent());
+                info!(
+                    "Parsed robots.txt, found {} sitemap URLs and {} Disallow rules",
+                    robots.sitemaps.len(),
+                    robots.disallow.len()
+                );
+            } else {
+                info!(
+                    "No robots.txt at {} (HTTP {})",
+                    robots_url, page.status_code
+                );
+            }
+        } else {
+            warn!("No pages returned for robots.txt");
+        }
+    } else {
+        warn!("Failed to fetch robots.txt for {}", robots_url);
+    }
+    Ok(robots)
+}
+
+// Parse robots.txt into its sitemap URLs, which apply to everyone, and the rules of
+// the groups naming our User-agent's product token (web-crawler for
+// web-crawler/0.1.0), or of the `*` groups when none do
+fn parse_robots_txt(content: &str, user_agent: &str) -> RobotsTxt {
+    let product = user_agent
+        .split(['/', ' '])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let mut sitemaps = Vec::new();
+    let mut ours = RobotsTxt::default();
+    let mut any = RobotsTxt::default();
+    let mut named = false;
+    // Consecutive User-agent lines share one group of rules
+    let mut in_agent_list = false;
+    let mut names_us = false;
+    let mut names_any = false;
+
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        let key = key.trim().to_ascii_lowercase();
+        if key == "sitemap" {
+            if !value.is_empty() {
+                sitemaps.push(value.to_string());
+            }
+            continue;
+        }
+        if key == "user-agent" {
+            if !in_agent_list {
+                names_us = false;
+                names_any = false;
+            }
+            in_agent_list = true;
+            let agent = value.to_ascii_lowercase();
+            names_any |= agent == "*";
+            names_us |= !product.is_empty() && agent == product;
+            named |= names_us;
+            continue;
+        }
+        in_agent_list = false;
+        // A group naming us as well as `*` is ours
+        let group = match (names_us, names_any) {
+            (true, _) => &mut ours,
+            (false, true) => &mut any,
+            (false, false) => continue,
+        };
+        match key.as_str() {
+            "allow" if !value.is_empty() => group.allow.push(value.to_string()),
+            // An empty Disallow allows everything
+            "disallow" if !value.is_empty() => group.disallow.push(value.to_string()),
+            "crawl-delay" => match value.parse::<f64>() {
+                Ok(secs) if secs >= 0.0 && secs.is_finite() => {
+                    group.crawl_delay = Some(Duration::from_secs_f64(secs));
+                }
+                _ => warn!("Ignoring invalid Crawl-delay in robots.txt: {}", value),
+            },
+            _ => {}
+        }
+    }
+    let mut robots = if named { ours } else { any };
+    robots.sitemaps = sitemaps;
+    debug!("Extracted sitemaps from robots.txt: {:?}", robots.sitemaps);
+    debug!(
+        "Extracted Disallow rules from robots.txt for {}: {:?}, Allow rules: {:?}",
+        if named { product.as_str() } else { "*" },
+        robots.disallow,
+        robots.allow
+    );
+    robots
+}
+
+// Match a robots.txt path pattern, supporting `*` wildcards and a trailing `$` anchor
+fn robots_pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = parts.next().and_then(|prefix| path.strip_prefix(prefix)) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        if anchored && i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    !anchored || rest.is_empty()
+}
+
+// Path and query of a URL, as matched by robots.txt rules
+fn url_path(url: &str) -> &str {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = without_scheme
+        .find('/')
+        .map_or("/", |start| &without_scheme[start..]);
+    path.split('#').next().unwrap_or_default()
+}
+
+// Page URLs collected from sitemaps, with their valid <lastmod> values
+struct SitemapUrls {
+    pages: Vec<String>,
+    lastmods: HashMap<String, String>,
+    // <image:loc> URLs, sorted, when config.collect_images is set
+    images: Vec<String>,
+}
+
+// Step 2: Load sitemaps recursively and extract page URLs
+async fn get_all_page_urls_from_sitemaps(
+    sitemap_urls: &[String],
+    config: &CrawlerConfig,
+    scheduler: &Scheduler,
+) -> Result<SitemapUrls, CrawlerError> {
+    let walk = SitemapWalk {
+        pages: std::sync::Mutex::new(HashMap::new()),
+        images: std::sync::Mutex::new(BTreeSet::new()),
+        visited: std::sync::Mutex::new(HashSet::new()),
+        fetch_slots: Semaphore::new(config.sitemap_concurrency.max(1)),
+    };
+    let fetched = future::join_all(sitemap_urls.iter().map(|sitemap_url| {
+        info!("Processing sitemap: {}", sitemap_url);
+        parse_sitemap_recursive(sitemap_url, 0, &walk, config, scheduler)
+    }))
+    .await;
+    // One unreadable sitemap leaves the others' URLs to crawl
+    let mut sitemaps_fetched = 0;
+    for (sitemap_url, result) in sitemap_urls.iter().zip(fetched) {
+        sitemaps_fetched += skip_failed_sitemap(sitemap_url, result);
+    }
+    let all_urls = walk.pages.into_inner().unwrap_or_else(|e| e.into_inner());
+    info!(
+        "Fetched {} sitemaps; total unique URLs from sitemaps: {}",
+        sitemaps_fetched,
+        all_urls.len()
+    );
+
+    let mut sitemap = SitemapUrls {
+        pages: Vec::with_capacity(all_urls.len()),
+        lastmods: HashMap::new(),
+        images: walk
+            .images
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner())
+            .into_iter()
+            .collect(),
+    };
+    if config.collect_images {
+        info!(
+            "Collected {} image URLs from sitemaps",
+            sitemap.images.len()
+        );
+    }
+    for (url, lastmod) in all_urls {
+        match lastmod {
+            Some(lastmod) if is_valid_lastmod(&lastmod) => {
+                sitemap.lastmods.insert(url.clone(), lastmod);
+            }
+            Some(lastmod) => debug!("Ignoring malformed lastmod {:?} for {}", lastmod, url),
+            None => {}
+        }
+        sitemap.pages.push(url);
+    }
+    // Stable order, so --limit and --dry-run give the same URLs every run
+    sitemap.pages.sort();
+    Ok(sitemap)
+}
+
+// Absolute http(s) form of a URL found in the document at `base`, so "/docs/a" in
+// https://site.com/sitemap.xml becomes https://site.com/docs/a
+fn resolve_url(base: &str, reference: &str) -> Option<String> {
+    let url = Url::parse(base).ok()?.join(reference.trim()).ok()?;
+    matches!(url.scheme(), "http" | "https").then(|| url.to_string())
+}
+
+// W3C datetime as used by sitemaps: YYYY-MM-DD, optionally followed by a time
+fn is_valid_lastmod(lastmod: &str) -> bool {
+    let bytes = lastmod.as_bytes();
+    bytes.len() >= 10
+        && bytes[..10].iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+        && (bytes.len() == 10 || bytes[10] == b'T')
+}
+
+// The calendar day of a valid lastmod, ignoring any time and time zone
+fn lastmod_date(lastmod: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(lastmod.get(..10)?, "%Y-%m-%d").ok()
+}
+
+// Record a page URL, keeping any lastmod already seen for it
+fn add_page_url(
+    all_urls: &std::sync::Mutex<HashMap<String, Option<String>>>,
+    url: String,
+    lastmod: Option<String>,
+    config: &CrawlerConfig,
+) {
+    let url = normalize_url(&url, &config.tracking_params);
+    debug!("Added page URL: {}", url);
+    let mut all_urls = all_urls.lock().unwrap_or_else(|e| e.into_inner());
+    let entry = all_urls.entry(url).or_default();
+    if lastmod.is_some() {
+        *entry = lastmod;
+    }
+}
+
+// Canonical form of a page URL for deduplication: drops the fragment, collapses a
+// single trailing slash, lowercases the scheme and host and removes tracking query
+// parameters. Other parameters are kept in their original order.
+fn normalize_url(url: &str, tracking_params: &[String]) -> String {
+    let url = url.split('#').next().unwrap_or_default();
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let (authority, path_and_query) = rest
+        .find(['/', '?'])
+        .map_or((rest, ""), |start| rest.split_at(start));
+    let (path, query) = path_and_query
+        .find('?')
+        .map_or((path_and_query, ""), |start| path_and_query.split_at(start));
+    let path = match path {
+        "" | "/" => "/",
+        _ => path.strip_suffix('/').unwrap_or(path),
+    };
+    format!(
+        "{}://{}{}{}",
+        scheme.to_ascii_lowercase(),
+        authority.to_ascii_lowercase(),
+        path,
+        strip_tracking_params(query, tracking_params)
+    )
+}
+
+// Query string (with its leading '?') minus parameters named in the denylist
+fn strip_tracking_params(query: &str, tracking_params: &[String]) -> String {
+    let Some(params) = query.strip_prefix('?') else {
+        return query.to_string();
+    };
+    let is_tracking = |param: &str| {
+        let name = param.split('=').next().unwrap_or_default();
+        tracking_params
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            })
+    };
+    let kept: Vec<&str> = params
+        .split('&')
+        .filter(|param| !param.is_empty() && !is_tracking(param))
+        .collect();
+    if kept.is_empty() {
+        String::new()
+    } else {
+        format!("?{}", kept.join("&"))
+    }
+}
+
+// What every sitemap read for one domain adds to, across all levels of nesting
+struct SitemapWalk {
+    // Page URL -> its <lastmod>, as written
+    pages: std::sync::Mutex<HashMap<String, Option<String>>>,
+    images: std::sync::Mutex<BTreeSet<String>>,
+    visited: std::sync::Mutex<HashSet<String>>,
+    // Shared by every level of nesting, so an index's children queue for the same slots
+    fetch_slots: Semaphore,
+}
+
+// Recursive sitemap parsing. The child sitemaps of an index are fetched concurrently,
+// at most --sitemap-concurrency at a time; returns how many sitemaps were read.
+// `walk.visited` holds every sitemap already read, so indexes that list each other end.
+async fn parse_sitemap_recursive(
+    sitemap_url: &str,
+    depth: usize,
+    walk: &SitemapWalk,
+    config: &CrawlerConfig,
+    scheduler: &Scheduler,
+) -> Result<usize, CrawlerError> {
+    if depth > config.sitemap_max_depth {
+        warn!(
+            "Not following sitemap {}: indexes are nested more than {} deep",
+            sitemap_url, config.sitemap_max_depth
+        );
+        return Ok(0);
+    }
+    let first_visit = walk
+        .visited
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(normalize_url(sitemap_url, &config.tracking_params));
+    if !first_visit {
+        debug!("Skipping sitemap {}: already read", sitemap_url);
+        return Ok(0);
+    }
+    let slot = walk
+        .fetch_slots
+        .acquire()
+        .await
+        .expect("sitemap semaphore is never closed");
+    let mut nested = Vec::new();
+    let mut website = new_website(sitemap_url, config, scheduler);
+    website.configuration.depth = 0;
+    if scrape_with_timeout(&mut website, config, scheduler)
+        .await
+        .is_err()
+    {
+        warn!(
+            "Timed out after {:?} fetching sitemap {}",
+            config.timeout(),
+            sitemap_url
+        );
+        return Ok(0);
+    }
+
+    if let Some(pages) = website.get_pages() {
+        if let Some(page) = pages.first() {
+            // An error page is not a sitemap, and parsing it as one would fail
+            if !page.status_code.is_success() {
+                info!("No sitemap at {} (HTTP {})", sitemap_url, page.status_code);
+                return Ok(0);
+            }
+            let bytes = page.get_html_bytes_u8();
+            debug!(
+                "Fetched sitemap content for {}, length: {} bytes",
+                sitemap_url,
+                bytes.len()
+            );
+            let html = decode_sitemap_body(sitemap_url, bytes)?;
+            // Plain-text sitemaps list one URL per line and have no markup to parse
+            if !html.starts_with('<') {
+                let added = add_text_sitemap_urls(&html, sitemap_url, &walk.pages, config);
+                debug!("Read {} URLs from text sitemap {}", added, sitemap_url);
+            } else {
+                let mut reader = NsReader::from_str(&html);
+
+                let mut buf = Vec::new();
+                let mut in_loc = false;
+                let mut in_lastmod = false;
+                let mut in_image_loc = false;
+                let mut current_url = String::new();
+                // A <url> entry's <lastmod> follows its <loc>, so hold the page until </url>
+                let mut pending_page: Option<String> = None;
+                let mut current_lastmod: Option<String> = None;
+                // Set by the root element: <sitemapindex> lists sitemaps, <urlset> lists pages
+                let mut is_index: Option<bool> = None;
+
+                // A malformed part ends the read, but keeps what came before it
+                let read = (|| -> Result<(), CrawlerError> {
+                    loop {
+                        match reader.read_resolved_event_into(&mut buf) {
+                            Ok((_, Event::Start(e))) if is_index.is_none() => {
+                                let root = e.local_name();
+                                if root.as_ref() != b"sitemapindex" && root.as_ref() != b"urlset" {
+                                    warn!(
+                                        "Unexpected root element <{}> in sitemap {}, reading it as a urlset",
+                                        String::from_utf8_lossy(root.as_ref()),
+                                        sitemap_url
+                                    );
+                                }
+                                is_index = Some(root.as_ref() == b"sitemapindex");
+                            }
+                            Ok((ns, Event::Start(e)))
+                                if is_sitemap_element(&ns, e.local_name(), b"loc") =>
+                            {
+                                in_loc = true;
+                            }
+                            Ok((ns, Event::Start(e)))
+                                if is_sitemap_element(&ns, e.local_name(), b"lastmod") =>
+                            {
+                                in_lastmod = true;
+                            }
+                            Ok((ns, Event::Start(e)))
+                                if config.collect_images && is_image_loc(&ns, e.local_name()) =>
+                            {
+                                in_image_loc = true;
+                            }
+                            Ok((_, Event::Text(e))) if in_image_loc => {
+                                match resolve_url(sitemap_url, &e.unescape()?) {
+                                    Some(image) => {
+                                        walk.images
+                                            .lock()
+                                            .unwrap_or_else(|e| e.into_inner())
+                                            .insert(image);
+                                    }
+                                    None => {
+                                        debug!("Skipping unresolvable image URL in {}", sitemap_url)
+                                    }
+                                }
+                            }
+                            Ok((ns, Event::End(e))) if is_image_loc(&ns, e.local_name()) => {
+                                in_image_loc = false;
+                            }
+                            Ok((_, Event::Text(e))) if in_loc => {
+                                current_url = e.unescape()?.to_string();
+                                debug!("Found URL in sitemap: {}", current_url);
+                            }
+                            Ok((_, Event::Text(e))) if in_lastmod => {
+                                current_lastmod = Some(e.unescape()?.trim().to_string());
+                            }
+                            Ok((ns, Event::End(e)))
+                                if is_sitemap_element(&ns, e.local_name(), b"loc")
+                                    && !current_url.is_empty() =>
+                            {
+                                in_loc = false;
+                                // A relative <loc> is relative to the sitemap itself
+                                match resolve_url(sitemap_url, &current_url) {
+                                    None => warn!(
+                                        "Skipping unresolvable URL {:?} in sitemap {}",
+                                        current_url, sitemap_url
+                                    ),
+                                    Some(url) if is_index == Some(true) => {
+                                        // Nested sitemap
+                                        debug!("Found nested sitemap: {}", url);
+                                        nested.push(url);
+                                    }
+                                    Some(url) => {
+                                        // Page URL
+                                        if let Some(page) = pending_page.take() {
+                                            add_page_url(
+                                                &walk.pages,
+                                                page,
+                                                current_lastmod.take(),
+                                                config,
+                                            );
+                                        }
+                                        pending_page = Some(url);
+                                    }
+                                }
+                                current_url.clear();
+                            }
+                            Ok((ns, Event::End(e)))
+                                if is_sitemap_element(&ns, e.local_name(), b"lastmod") =>
+                            {
+                                in_lastmod = false;
+                            }
+                            Ok((ns, Event::End(e)))
+                                if is_sitemap_element(&ns, e.local_name(), b"url") =>
+                            {
+                                if let Some(page) = pending_page.take() {
+                                    add_page_url(&walk.pages, page, current_lastmod.take(), config);
+                                }
+                                current_lastmod = None;
+                            }
+                            Ok((_, Event::Eof)) => break,
+                            Err(e) => return Err(e.into()),
+                            _ => {}
+                        }
+                        buf.clear();
+                    }
+                    Ok(())
+                })();
+                if let Err(e) = read {
+                    warn!(
+                        "Stopped reading sitemap {} at an XML error: {}",
+                        sitemap_url, e
+                    );
+                }
+                if let Some(page) = pending_page.take() {
+                    add_page_url(&walk.pages, page, current_lastmod.take(), config);
+                }
+            }
+        } else {
+            warn!("No pages returned for sitemap {}", sitemap_url);
+        }
+    } else {
+        warn!("Failed to fetch sitemap {}", sitemap_url);
+    }
+    // Children wait for slots too; holding this one while they do could deadlock
+    drop(slot);
+
+    let children = future::join_all(nested.iter().map(|child| {
+        Box::pin(parse_sitemap_recursive(
+            child,
+            depth + 1,
+            walk,
+            config,
+            scheduler,
+        ))
+    }))
+    .await;
+    let mut fetched = 1;
+    for (child, result) in nested.iter().zip(children) {
+        fetched += skip_failed_sitemap(child, result);
+    }
+    Ok(fetched)
+}
+
+// How many sitemaps a parse read, warning about and counting none for one that
+// failed. Page URLs it listed before the failure are still crawled.
+fn skip_failed_sitemap(sitemap_url: &str, result: Result<usize, CrawlerError>) -> usize {
+    result.unwrap_or_else(|e| {
+        warn!("Skipping the rest of sitemap {}: {}", sitemap_url, e);
+        0
+    })
+}
+
+// Whether an element is the sitemap protocol's `local_name`, unprefixed or in the
+// sitemaps.org namespace under any prefix (<loc>, <sm:loc>), and not an extension's
+// element of the same name such as <image:loc>
+fn is_sitemap_element(ns: &ResolveResult, name: LocalName, local_name: &[u8]) -> bool {
+    // 0.9 from sitemaps.org, and the 0.84 Google used before it
+    let in_sitemap_namespace =
+        matches!(ns, ResolveResult::Unbound) || in_namespace(ns, b"/schemas/sitemap/");
+    in_sitemap_namespace && name.as_ref() == local_name
+}
+
+// Whether an element's namespace URI contains `marker`, e.g. /schemas/sitemap-image/
+fn in_namespace(ns: &ResolveResult, marker: &[u8]) -> bool {
+    match ns {
+        ResolveResult::Bound(Namespace(uri)) => {
+            uri.windows(marker.len()).any(|window| window == marker)
+        }
+        ResolveResult::Unbound | ResolveResult::Unknown(_) => false,
+    }
+}
+
+// Whether an element is an image sitemap's <image:loc>, whatever its prefix
+fn is_image_loc(ns: &ResolveResult, name: LocalName) -> bool {
+    in_namespace(ns, b"/schemas/sitemap-image/") && name.as_ref() == b"loc"
+}
+
+// Record the page URLs of a plain-text sitemap: each line holding an absolute http(s)
+// URL. Blank lines and # comments are skipped; returns how many URLs were added.
+fn add_text_sitemap_urls(
+    text: &str,
+    sitemap_url: &str,
+    all_urls: &std::sync::Mutex<HashMap<String, Option<String>>>,
+    config: &CrawlerConfig,
+) -> usize {
+    let mut added = 0;
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match Url::parse(line) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {
+                add_page_url(all_urls, url.to_string(), None, config);
+                added += 1;
+            }
+            _ => warn!(
+                "Skipping line {:?} in text sitemap {}: not an absolute URL",
+                line, sitemap_url
+            ),
+        }
+    }
+    added
+}
+
+// Decompress gzipped sitemaps (sitemap.xml.gz) before they reach the XML reader
+fn decode_sitemap_body(sitemap_url: &str, bytes: &[u8]) -> Result<String, CrawlerError> {
+    if !bytes.starts_with(&[0x1f, 0x8b]) {
+        if sitemap_url.ends_with(".gz") {
+            debug!(
+                "Sitemap {} has a .gz suffix but was served uncompressed",
+                sitemap_url
+            );
+        }
+        return Ok(trim_xml_start(&String::from_utf8_lossy(bytes)).to_string());
+    }
+
+    let mut xml = String::new();
+    GzDecoder::new(bytes)
+        .read_to_string(&mut xml)
+        .map_err(|e| {
+            CrawlerError::Decode(format!(
+                "Failed to decompress gzipped sitemap {}: {}",
+                sitemap_url, e
+            ))
+        })?;
+    debug!(
+        "Decompressed sitemap {} to {} bytes",
+        sitemap_url,
+        xml.len()
+    );
+    Ok(trim_xml_start(&xml).to_string())
+}
+
+// Drop a UTF-8 byte order mark and any whitespace some servers send ahead of
+// `<?xml`, which the reader would otherwise reject or read as an empty document
+fn trim_xml_start(xml: &str) -> &str {
+    xml.trim_start_matches('\u{feff}').trim_start()
+}
+
+// Where sites commonly publish an RSS or Atom feed
+const FEED_PATHS: [&str; 3] = ["/feed", "/rss.xml", "/atom.xml"];
+
+// Step 3b: Article URLs from the first common feed path that has any; a missing or
+// unreadable feed is skipped like a missing sitemap
+async fn get_page_urls_from_feeds(
+    domain: &str,
+    config: &CrawlerConfig,
+    scheduler: &Scheduler,
+) -> Vec<String> {
+    for path in FEED_PATHS {
+        let feed_url = format!("{}{}", domain, path);
+        info!("Trying feed: {}", feed_url);
+        let mut website = new_website(&feed_url, config, scheduler);
+        website.configuration.depth = 0;
+        website.with_limit(1);
+        if scrape_with_timeout(&mut website, config, scheduler)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Timed out after {:?} fetching feed {}",
+                config.timeout(),
+                feed_url
+            );
+            continue;
+        }
+        let Some(page) = website.get_pages().and_then(|pages| pages.first()) else {
+            continue;
+        };
+        if !page.status_code.is_success() {
+            info!("No feed at {} (HTTP {})", feed_url, page.status_code);
+            continue;
+        }
+        let xml = String::from_utf8_lossy(page.get_html_bytes_u8());
+        match parse_feed(&feed_url, trim_xml_start(&xml), config) {
+            Ok(urls) if !urls.is_empty() => return urls,
+            Ok(_) => info!("No article links in {}", feed_url),
+            Err(e) => warn!("Skipping unreadable feed {}: {}", feed_url, e),
+        }
+    }
+    Vec::new()
+}
+
+// Links of the items in an RSS feed (<item><link>) or the entries in an Atom feed
+// (<entry><link href>), keeping those on the feed's own host
+fn parse_feed(
+    feed_url: &str,
+    xml: &str,
+    config: &CrawlerConfig,
+) -> Result<Vec<String>, CrawlerError> {
+    let host = Url::parse(feed_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string));
+    let mut reader = Reader::from_str(xml);
+    let mut buf = Vec::new();
+    let mut links = Vec::new();
+    // Set by the root element; anything but <rss>, <rdf:RDF> or <feed> is not a feed
+    let mut is_feed: Option<bool> = None;
+    let mut in_item = false;
+    let mut in_link = false;
+
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) if is_feed.is_none() => {
+                is_feed = Some(matches!(e.name().as_ref(), b"rss" | b"rdf:RDF" | b"feed"));
+                if is_feed == Some(false) {
+                    debug!("{} is not an RSS or Atom feed", feed_url);
+                    break;
+                }
+            }
+            Event::Start(e) if matches!(e.name().as_ref(), b"item" | b"entry") => in_item = true,
+            Event::End(e) if matches!(e.name().as_ref(), b"item" | b"entry") => in_item = false,
+            // RSS: the link is the element's text
+            Event::Start(e) if in_item && e.name().as_ref() == b"link" => {
+                in_link = true;
+                if let Some(href) = atom_link_href(&e)? {
+                    links.push(href);
+                }
+            }
+            // Atom: an empty <link> whose href points at the entry's page
+            Event::Empty(e) if in_item && e.name().as_ref() == b"link" => {
+                if let Some(href) = atom_link_href(&e)? {
+                    links.push(href);
+                }
+            }
+            Event::Text(e) if in_link => {
+                let text = e.unescape()?.trim().to_string();
+                if !text.is_empty() {
+                    links.push(text);
+                }
+            }
+            Event::CData(e) if in_link => {
+                let text = String::from_utf8_lossy(&e).trim().to_string();
+                if !text.is_empty() {
+                    links.push(text);
+                }
+            }
+            Event::End(e) if e.name().as_ref() == b"link" => in_link = false,
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    let mut urls: Vec<String> = links
+        .iter()
+        .filter_map(|link| Url::parse(feed_url).ok()?.join(link).ok())
+        .filter(|url| url.host_str().map(str::to_string) == host)
+        .map(|url| normalize_url(url.as_str(), &config.tracking_params))
+        .collect();
+    urls.sort();
+    urls.dedup();
+    debug!("Found {} article URLs in feed {}", urls.len(), feed_url);
+    Ok(urls)
+}
+
+// The href of an Atom <link> that points at the entry itself (rel="alternate", the
+// default), or None for enclosures, replies and RSS links without attributes
+fn atom_link_href(element: &quick_xml::events::BytesStart) -> Result<Option<String>, CrawlerError> {
+    let mut href = None;
+    let mut alternate = true;
+    for attr in element.attributes().flatten() {
+        let value = attr.unescape_value()?.trim().to_string();
+        match attr.key.as_ref() {
+            b"href" => href = Some(value),
+            b"rel" => alternate = value == "alternate",
+            _ => {}
+        }
+    }
+    Ok(href.filter(|_| alternate))
+}
+
+// Spider website for `url` carrying the request settings shared by every fetch
+fn new_website(url: &str, config: &CrawlerConfig, scheduler: &Scheduler) -> Website {
+    let mut website = Website::new(url);
+    let user_agent = scheduler.next_user_agent(config);
+    if config.user_agents.len() > 1 {
+        debug!("Requesting {} as {}", url, user_agent);
+    }
+    website.with_user_agent(Some(user_agent));
+    website.with_redirect_limit(config.max_redirects);
+    // Validated by parse_args, so the headers always build
+    if let Ok(headers) = config.request_headers()
+        && !headers.is_empty()
+    {
+        website.with_headers(Some(headers));
+    }
+    if let Some(cookie) = &config.cookie {
+        website.with_cookies(cookie);
+    }
+    // Validated by parse_args too
+    if let Ok(Some(proxy)) = config.proxy_url() {
+        website.with_proxies(Some(vec![proxy]));
+    }
+    website
+}
+
+// Scrape a single URL once its host's delay has passed, abandoning it once the
+// request timeout has passed. Every request except the native crawl's goes through here.
+async fn scrape_with_timeout(
+    website: &mut Website,
+    config: &CrawlerConfig,
+    scheduler: &Scheduler,
+) -> Result<(), tokio::time::error::Elapsed> {
+    scheduler.pace(website.get_url().inner()).await;
+    let scraped = tokio::time::timeout(config.timeout(), website.scrape()).await;
+    let bytes = website
+        .get_pages()
+        .map(|pages| {
+            pages
+                .iter()
+                .map(|page| page.get_html_bytes_u8().len())
+                .sum()
+        })
+        .unwrap_or(0);
+    scheduler.traffic.record(1, bytes);
+    scraped
+}
+
+// Pause between the native crawl's requests when neither --delay nor robots.txt sets one
+const NATIVE_CRAWL_DELAY_MS: u64 = 100;
+
+// Crawled pages spider may hold for the native crawl's byte counter before it drops
+// the oldest
+const NATIVE_CRAWL_PAGE_QUEUE: usize = 1024;
+
+// Step 4: Native crawl if no robots.txt or sitemap.xml
+async fn native_crawl(
+    domain: &str,
+    config: &CrawlerConfig,
+    scheduler: &Scheduler,
+    delay: Duration,
+) -> Result<Vec<String>, CrawlerError> {
+    info!("Starting native crawl for {}", domain);
+    // Each --crawl-prefix is a crawl of its own, seeded at and kept under that path
+    let prefixes: Vec<String> = config
+        .crawl_prefixes
+        .iter()
+        .map(|prefix| format!("/{}", prefix.trim_start_matches('/')))
+        .collect();
+    let seeds: Vec<Option<&str>> = if prefixes.is_empty() {
+        vec![None]
+    } else {
+        prefixes
+            .iter()
+            .map(|prefix| Some(prefix.as_str()))
+            .collect()
+    };
+    let mut links = Vec::new();
+    for prefix in seeds {
+        let budget = config.max_links.map(|max| max.saturating_sub(links.len()));
+        if budget == Some(0) {
+            break;
+        }
+        links.extend(crawl_links(domain, prefix, budget, config, scheduler, delay).await);
+    }
+
+    let seed_host = Url::parse(domain)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let found = links.len();
+    let mut urls: Vec<String> = links
+        .iter()
+        .filter(|link| {
+            Url::parse(link)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .is_some_and(|host| on_site(&host, &seed_host, config.allow_subdomains))
+        })
+        .filter(|link| {
+            prefixes.is_empty()
+                || prefixes
+                    .iter()
+                    .any(|prefix| url_path(link).starts_with(prefix.as_str()))
+        })
+        .map(|link| normalize_url(link, &config.tracking_params))
+        .collect();
+    if urls.len() < found {
+        debug!(
+            "Dropped {} links that leave {} or the crawl prefixes",
+            found - urls.len(),
+            seed_host
+        );
+    }
+    urls.sort();
+    urls.dedup();
+    if let Some(max_links) = config.max_links
+        && urls.len() >= max_links
+    {
+        info!("Native crawl stopped at its budget of {} links", max_links);
+        urls.truncate(max_links);
+    }
+    info!("Native crawl collected {} URLs", urls.len());
+    Ok(urls)
+}
+
+// One spider crawl from the domain's root, or from `prefix` without leaving it,
+// visiting at most `budget` pages
+async fn crawl_links(
+    domain: &str,
+    prefix: Option<&str>,
+    budget: Option<usize>,
+    config: &CrawlerConfig,
+    scheduler: &Scheduler,
+    delay: Duration,
+) -> Vec<String> {
+    let seed = format!("{}{}", domain, prefix.unwrap_or_default());
+    let mut website = new_website(&seed, config, scheduler);
+    website.configuration.depth = config.depth;
+    if let Some(prefix) = prefix {
+        // Spider matches these against whole URLs, with or without the host's www.
+        let pattern = format!("^https?://[^/?#]+{}", regex::escape(prefix));
+        website.with_whitelist_url(Some(vec![pattern.into()]));
+    }
+    // spider treats depth 0 as unlimited; ours means the seed page alone
+    if config.depth == 0 {
+        website.with_limit(1);
+    } else if let Some(budget) = budget {
+        website.with_limit(u32::try_from(budget).unwrap_or(u32::MAX));
+    }
+    website.configuration.delay = delay.as_millis() as u64;
+    // A whole crawl can outlast the timeout, so bound each of its requests instead
+    website.with_request_timeout(Some(config.timeout()));
+    website.with_subdomains(config.allow_subdomains);
+    if config.meta_robots {
+        website.with_on_should_crawl_callback(Some(follows_links));
+    }
+    // Spider keeps no crawled pages, so count their bytes as it hands them out
+    let counter = website.subscribe(NATIVE_CRAWL_PAGE_QUEUE).map(|mut pages| {
+        tokio::spawn(async move {
+            let mut bytes = 0;
+            loop {
+                match pages.recv().await {
+                    Ok(page) => bytes += page.get_html_bytes_u8().len(),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        debug!("Native crawl byte count missed {} pages", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break bytes,
+                }
+            }
+        })
+    });
+    // website.configuration.use_browser = get_fetch_mode(domain);
+    website.crawl().await;
+    // Closes the subscription, so the counter sees the last page and stops
+    website.unsubscribe();
+    let bytes = match counter {
+        Some(counter) => counter.await.unwrap_or_default(),
+        None => 0,
+    };
+    scheduler.traffic.record(website.size(), bytes);
+    website
+        .get_links()
+        .iter()
+        .map(|link| link.as_ref().to_string())
+        .collect()
+}
+
+// Spider's check before queueing a crawled page's links: not when it says nofollow
+fn follows_links(page: &spider::page::Page) -> bool {
+    let nofollow = extract_meta_robots(&page.get_html()).nofollow;
+    if nofollow {
+        debug!(
+            "Not following the links on {}: its meta robots tag says nofollow",
+            page.get_url()
+        );
+    }
+    !nofollow
+}
+
+// Whether a link's host belongs to the site being crawled: the seed host itself or
+// its www. twin, or with allow_subdomains anything under the same registrable domain
+fn on_site(host: &str, seed_host: &str, allow_subdomains: bool) -> bool {
+    let host = host.to_ascii_lowercase();
+    let seed_host = seed_host.to_ascii_lowercase();
+    if host.trim_start_matches("www.") == seed_host.trim_start_matches("www.") {
+        return true;
+    }
+    allow_subdomains && registrable_domain(&host) == registrable_domain(&seed_host)
+}
+
+// Whether two URLs are on hosts under the same registrable domain, e.g. a sitemap on
+// cdn.example.com for www.example.com
+fn same_registrable_domain(url: &str, other: &str) -> bool {
+    let host = |url: &str| {
+        Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+    };
+    match (host(url), host(other)) {
+        (Some(host), Some(other)) => registrable_domain(&host) == registrable_domain(&other),
+        _ => false,
+    }
+}
+
+// The part of a host name a site owner registers, e.g. example.com for
+// docs.example.com. Without a public suffix list, a two-letter country code behind
+// a short label (example.co.uk, example.com.au) is taken to be a suffix of its own.
+fn registrable_domain(host: &str) -> &str {
+    // IP addresses have no subdomains
+    if host.parse::<std::net::IpAddr>().is_ok() || host.starts_with('[') {
+        return host;
+    }
+    let labels: Vec<&str> = host.split('.').collect();
+    let keep = match labels.as_slice() {
+        [.., second, tld] if tld.len() == 2 && second.len() <= 3 => 3,
+        _ => 2,
+    };
+    if labels.len() <= keep {
+        return host;
+    }
+    let skip: usize = labels[..labels.len() - keep]
+        .iter()
+        .map(|label| label.len() + 1)
+        .sum();
+    &host[skip..]
+}
+
+// Delay before the first retry; doubled after every failed attempt
+const RETRY_BASE_DELAY_MS: u64 = 500;
+
+// Step 5: Load HTML from a URL, retrying failed fetches with exponential backoff.
+// The page's fetch `slot` is given up between attempts.
+async fn load_html<'a>(
+    url: &str,
+    domain: &str,
+    config: &CrawlerConfig,
+    scheduler: &'a Scheduler,
+    slot: &mut Option<FetchSlot<'a>>,
+) -> Result<FetchedPage, CrawlerError> {
+    let attempts = config.fetch_attempts.max(1);
+    let mut backoff = Duration::from_millis(RETRY_BASE_DELAY_MS);
+    let mut attempt = 1;
+    loop {
+        if let Some(remaining) = scheduler.breaker_open(url) {
+            return Err(CrawlerError::Fetch(format!(
+                "skipped while {} keeps failing; it is tried again in {:.0?}",
+                url_host(url),
+                remaining
+            )));
+        }
+        let fetched = fetch_html(url, domain, config, scheduler).await;
+        // Only timeouts, server errors and the like suggest the host is in trouble
+        scheduler.record_request(url, !matches!(fetched, Err(FetchError::Retriable(_))));
+        match fetched {
+            Ok(page) => return Ok(page),
+            Err(FetchError::RateLimited { retry_after }) if attempt < attempts => {
+                // Wait as long as the server asks, within reason; otherwise back off
+                let wait = retry_after.map_or(backoff, |retry_after| {
+                    retry_after.min(Duration::from_secs(config.max_retry_after_secs))
+                });
+                warn!(
+                    "Attempt {}/{} for {} was rate limited (HTTP 429); retrying in {:?}",
+                    attempt, attempts, url, wait
+                );
+                scheduler.wait_for_retry(slot, url, wait).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(FetchError::RateLimited { .. }) => {
+                return Err(CrawlerError::Fetch(format!(
+                    "HTTP 429 Too Many Requests for {} after {} attempts",
+                    url, attempts
+                )));
+            }
+            Err(FetchError::Retriable(e)) if attempt < attempts => {
+                warn!(
+                    "Attempt {}/{} for {} failed: {}; retrying in {:?}",
+                    attempt, attempts, url, e, backoff
+                );
+                scheduler.wait_for_retry(slot, url, backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(FetchError::Retriable(e) | FetchError::Fatal(e)) => {
+                return Err(CrawlerError::Fetch(e));
+            }
+            Err(FetchError::Status(status, message)) => {
+                return Err(CrawlerError::Status { status, message });
+            }
+            Err(FetchError::NotHtml { content_type, body }) => {
+                return Err(CrawlerError::NotHtml { content_type, body });
+            }
+        }
+    }
+}
+
+// 404 Not Found and 410 Gone: the page is not there, however often it is asked for
+fn is_gone(status: u16) -> bool {
+    matches!(status, 404 | 410)
+}
+
+// How long a Retry-After header asks to wait: either a number of seconds or an HTTP
+// date, which counts as no wait once it has passed
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (at.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+// HTML of a fetched page, the URL it was finally served from, the same-site
+// canonical URL it declares, if any, and whether its meta robots tag says noindex
+struct FetchedPage {
+    html: String,
+    final_url: String,
+    canonical: Option<String>,
+    noindex: bool,
+}
+
+/// Everything that can stop a crawl step, by kind, so callers can tell a page that
+/// could not be fetched from a problem writing the output
+#[derive(Debug)]
+pub enum CrawlerError {
+    /// A request failed or returned an unusable response
+    Fetch(String),
+    /// The server answered with an error status, such as 404, that retrying won't change
+    Status { status: u16, message: String },
+    /// A sitemap or feed was not well-formed XML
+    XmlParse(quick_xml::Error),
+    /// Reading or writing local files
+    Io(std::io::Error),
+    /// A body could not be decoded, e.g. corrupt gzip or invalid JSON
+    Decode(String),
+    /// A setting is unusable, e.g. a malformed --include pattern
+    Config(String),
+    /// The URL served a file such as a PDF or image rather than a page
+    NotHtml { content_type: String, body: Vec<u8> },
+}
+
+impl std::fmt::Display for CrawlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CrawlerError::Fetch(message)
+            | CrawlerError::Status { message, .. }
+            | CrawlerError::Decode(message)
+            | CrawlerError::Config(message) => f.write_str(message),
+            CrawlerError::NotHtml { content_type, .. } => {
+                write!(f, "not an HTML page ({})", content_type)
+            }
+            CrawlerError::XmlParse(e) => e.fmt(f),
+            CrawlerError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CrawlerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CrawlerError::XmlParse(e) => Some(e),
+            CrawlerError::Io(e) => Some(e),
+            CrawlerError::Fetch(_)
+            | CrawlerError::Status { .. }
+            | CrawlerError::Decode(_)
+            | CrawlerError::Config(_)
+            | CrawlerError::NotHtml { .. } => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CrawlerError {
+    fn from(e: std::io::Error) -> Self {
+        CrawlerError::Io(e)
+    }
+}
+
+impl From<quick_xml::Error> for CrawlerError {
+    fn from(e: quick_xml::Error) -> Self {
+        CrawlerError::XmlParse(e)
+    }
+}
+
+impl From<globset::Error> for CrawlerError {
+    fn from(e: globset::Error) -> Self {
+        CrawlerError::Config(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for CrawlerError {
+    fn from(e: serde_json::Error) -> Self {
+        CrawlerError::Decode(e.to_string())
+    }
+}
+
+// Why a single fetch attempt failed; only transient failures are retried
+enum FetchError {
+    Retriable(String),
+    Fatal(String),
+    // An HTTP error status that is not worth retrying
+    Status(u16, String),
+    // HTTP 429, with how long the Retry-After header asks to wait, if it says
+    RateLimited { retry_after: Option<Duration> },
+    // The response was fine but is not a page, so there is nothing to retry
+    NotHtml { content_type: String, body: Vec<u8> },
+}
+
+// What a HEAD request says about a URL before its body is fetched
+enum HeadCheck {
+    // HTML, or HEAD could not tell: fetch it as usual
+    Fetch,
+    NotHtml(String),
+    TooLarge(u64),
+}
+
+// HTTP client for --prefetch-head, sending the same identity and headers as spider
+fn new_head_client(config: &CrawlerConfig) -> Result<spider::reqwest::Client, CrawlerError> {
+    let mut headers = config.request_headers().map_err(CrawlerError::Config)?;
+    if let Some(cookie) = &config.cookie {
+        let cookie = HeaderValue::from_str(cookie)
+            .map_err(|_| CrawlerError::Config(format!("Invalid cookie: {}", cookie)))?;
+        headers.insert(spider::reqwest::header::COOKIE, cookie);
+    }
+    let mut builder = spider::reqwest::Client::builder();
+    if let Some(proxy) = config.proxy_url().map_err(CrawlerError::Config)? {
+        let proxy = spider::reqwest::Proxy::all(&proxy)
+            .map_err(|e| CrawlerError::Config(format!("Invalid proxy URL {}: {}", proxy, e)))?;
+        builder = builder.proxy(proxy);
+    }
+    builder
+        .user_agent(&config.user_agent)
+        .default_headers(headers)
+        .redirect(spider::reqwest::redirect::Policy::limited(
+            config.max_redirects,
+        ))
+        .timeout(config.timeout())
+        .build()
+        .map_err(|e| CrawlerError::Fetch(format!("failed to build HTTP client: {}", e)))
+}
+
+// Step 4b: Ask for a URL's headers alone, so binaries and oversized files listed in a
+// sitemap are skipped without downloading them. Servers that refuse HEAD (405),
+// errors and missing headers all fall back to the normal GET.
+async fn head_check(
+    client: &spider::reqwest::Client,
+    url: &str,
+    config: &CrawlerConfig,
+    scheduler: &Scheduler,
+) -> HeadCheck {
+    scheduler.pace(url).await;
+    let user_agent = scheduler.next_user_agent(config);
+    if config.user_agents.len() > 1 {
+        debug!("Sending HEAD {} as {}", url, user_agent);
+    }
+    let request = client
+        .head(url)
+        .header(spider::reqwest::header::USER_AGENT, user_agent);
+    let response = request.send().await;
+    scheduler.traffic.record(1, 0);
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            debug!("HEAD {} failed, falling back to GET: {}", url, e);
+            return HeadCheck::Fetch;
+        }
+    };
+    if !response.status().is_success() {
+        debug!(
+            "HEAD {} returned {}, falling back to GET",
+            url,
+            response.status()
+        );
+        return HeadCheck::Fetch;
+    }
+    let headers = response.headers();
+    if let Some(length) = headers
+        .get(spider::reqwest::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        && length > config.max_convert_bytes as u64
+    {
+        return HeadCheck::TooLarge(length);
+    }
+    // Assets are wanted whatever their type, so only the size can rule them out
+    if !config.include_assets
+        && let Some(content_type) = headers
+            .get(spider::reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+        && !is_html_content_type(content_type)
+    {
+        return HeadCheck::NotHtml(content_type.to_string());
+    }
+    HeadCheck::Fetch
+}
+
+// Content types that html_to_markdown can make sense of; XML covers XHTML served as such
+fn is_html_content_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime == "text/html" || mime.ends_with("/xml") || mime.ends_with("+xml")
+}
+
+// File extensions that are never HTML, checked before fetching
+const NON_HTML_EXTENSIONS: &[&str] = &[
+    "pdf", "jpg", "jpeg", "png", "gif", "webp", "svg", "ico", "bmp", "mp3", "mp4", "mov", "avi",
+    "webm", "zip", "gz", "tgz", "tar", "rar", "7z", "exe", "dmg", "doc", "docx", "xls", "xlsx",
+    "ppt", "pptx",
+];
+
+fn has_non_html_extension(url: &str) -> bool {
+    let Ok(url) = Url::parse(url) else {
+        return false;
+    };
+    let file_name = url.path().rsplit('/').next().unwrap_or_default();
+    file_name.rsplit_once('.').is_some_and(|(_, extension)| {
+        NON_HTML_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+    })
+}
+
+// A single attempt at fetching one page
+async fn fetch_html(
+    url: &str,
+    domain: &str,
+    config: &CrawlerConfig,
+    scheduler: &Scheduler,
+) -> Result<FetchedPage, FetchError> {
+    debug!("Fetching HTML for {}", url);
+    let mut website = new_website(url, config, scheduler);
+    website.configuration.depth = 0;
+    // depth = 0 alone does not stop spider from following the page's links
+    website.with_limit(1);
+    let use_browser = get_fetch_mode(domain, config);
+    if use_browser {
+        warn!(
+            "Browser rendering is not enabled in this build, using HTTP for {}",
+            url
+        );
+    }
+    if scrape_with_timeout(&mut website, config, scheduler)
+        .await
+        .is_err()
+    {
+        warn!("Request for {} timed out after {:?}", url, config.timeout());
+        return Err(FetchError::Retriable(format!(
+            "timed out after {:?}",
+            config.timeout()
+        )));
+    }
+
+    let Some(page) = website.get_pages().and_then(|pages| pages.first()) else {
+        return Err(FetchError::Retriable("no page returned".to_string()));
+    };
+    let status = page.status_code;
+    let content_type = page
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get("content-type"))
+        .and_then(|value| value.to_str().ok());
+    // Only a conversion limit: spider has read the whole body by now, but converting
+    // it would need several copies more. head_check is what avoids the download
+    let body_bytes = page.get_html_bytes_u8().len();
+    if body_bytes > config.max_convert_bytes {
+        warn!(
+            "Skipping {}: its {} byte response is over the {} byte limit",
+            url, body_bytes, config.max_convert_bytes
+        );
+        return Err(FetchError::Fatal(format!(
+            "response of {} bytes exceeds max_convert_bytes ({})",
+            body_bytes, config.max_convert_bytes
+        )));
+    }
+    if status.is_success()
+        && let Some(content_type) = content_type.filter(|value| !is_html_content_type(value))
+    {
+        return Err(FetchError::NotHtml {
+            content_type: content_type.to_string(),
+            body: page.get_html_bytes_u8().to_vec(),
+        });
+    }
+    let html = decode_html(page.get_html_bytes_u8(), content_type);
+    if status.is_success() {
+        // A confirmed 2xx response is final, even with an empty body
+        debug!(
+            "Successfully fetched HTML for {}, length: {} bytes",
+            url,
+            html.len()
+        );
+        let final_url = normalize_url(
+            page.final_redirect_destination.as_deref().unwrap_or(url),
+            &config.tracking_params,
+        );
+        if final_url != url {
+            debug!("{} redirected to {}", url, final_url);
+        }
+        let canonical = extract_canonical(&html, &final_url, &config.tracking_params)
+            .filter(|canonical| *canonical != final_url);
+        if let Some(canonical) = &canonical {
+            debug!("{} declares {} as its canonical URL", final_url, canonical);
+        }
+        let noindex = extract_meta_robots(&html).noindex;
+        Ok(FetchedPage {
+            html,
+            final_url,
+            canonical,
+            noindex,
+        })
+    } else if page
+        .error_status
+        .as_deref()
+        .is_some_and(|error| error.contains("redirect"))
+    {
+        // Following the same loop again would fail the same way
+        warn!(
+            "Gave up on {} after hitting the limit of {} redirects",
+            url, config.max_redirects
+        );
+        Err(FetchError::Fatal(format!(
+            "more than {} redirects",
+            config.max_redirects
+        )))
+    } else if status == spider::reqwest::StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = page
+            .headers
+            .as_ref()
+            .and_then(|headers| headers.get("retry-after"))
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        Err(FetchError::RateLimited { retry_after })
+    } else if status.is_server_error() || (html.is_empty() && !is_gone(status.as_u16())) {
+        Err(FetchError::Retriable(format!(
+            "HTTP {} for {}",
+            status, url
+        )))
+    } else {
+        Err(FetchError::Status(
+            status.as_u16(),
+            format!("HTTP {} for {}", status, url),
+        ))
+    }
+}
+
+// Transcode a page body to UTF-8 using the charset from the Content-Type header or a
+// <meta> tag, defaulting to UTF-8; a byte order mark overrides both
+fn decode_html(bytes: &[u8], content_type: Option<&str>) -> String {
+    let label = content_type
+        .and_then(charset_parameter)
+        .or_else(|| meta_charset(bytes));
+    let encoding = match label {
+        Some(label) => Encoding::for_label(label.as_bytes()).unwrap_or_else(|| {
+            debug!("Unknown charset {:?}, decoding as UTF-8", label);
+            UTF_8
+        }),
+        None => UTF_8,
+    };
+    let (html, actual, had_errors) = encoding.decode(bytes);
+    if actual != UTF_8 {
+        debug!("Decoded page from {}", actual.name());
+    }
+    if had_errors {
+        debug!("Page body had bytes invalid in {}", actual.name());
+    }
+    html.into_owned()
+}
+
+// The charset parameter of a Content-Type value, e.g. "text/html; charset=ISO-8859-1"
+fn charset_parameter(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches(['"', '\'']).to_string())
+    })
+}
+
+// Charset declared by <meta charset> or <meta http-equiv="Content-Type">, which must
+// appear within the first 1024 bytes
+fn meta_charset(bytes: &[u8]) -> Option<String> {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]).to_ascii_lowercase();
+    let mut search_from = 0;
+    while let Some(pos) = head[search_from..].find("<meta") {
+        let start = search_from + pos;
+        let end = head[start..]
+            .find('>')
+            .map_or(head.len(), |end| start + end);
+        search_from = end;
+        let attrs = &head[start + "<meta".len()..end];
+        if let Some(charset) = extract_attribute(attrs, "charset") {
+            return Some(charset.trim().to_string());
+        }
+        if extract_attribute(attrs, "http-equiv").is_some_and(|v| v == "content-type")
+            && let Some(content) = extract_attribute(attrs, "content")
+        {
+            return charset_parameter(&content);
+        }
+    }
+    None
+}
+
+// Step 6: Determine fetch mode based on domain
+fn get_fetch_mode(domain: &str, config: &CrawlerConfig) -> bool {
+    let use_browser = config
+        .browser_domains
+        .iter()
+        .any(|pattern| domain.contains(pattern.as_str()));
+    debug!("Using browser mode for {}: {}", domain, use_browser);
+    use_browser // true for browser (SPA), false for HTTP (SSR)
+}
+
+// Markdown document for a page: YAML frontmatter, the title as an H1, then the body.
+// The frontmatter names the crawler version that wrote it beside the crawl time.
+fn render_page(page: &PageResult) -> String {
+    let mut document = String::from("---\n");
+    if let Some(title) = &page.title {
+        document.push_str(&format!("title: {}\n", yaml_string(title)));
+    }
+    document.push_str(&format!("source_url: {}\n", yaml_string(&page.final_url)));
+    document.push_str(&format!(
+        "date_crawled: {}\n",
+        page.crawled_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+    ));
+    document.push_str(&format!("word_count: {}\n", page.word_count));
+    document.push_str(&format!("reading_minutes: {}\n", page.reading_minutes));
+    document.push_str(&format!("generator: {}\n", yaml_string(GENERATOR)));
+    document.push_str("---\n\n");
+    if let Some(title) = &page.title {
+        document.push_str(&format!("# {}\n\n", title));
+    }
+    document.push_str(&page.markdown);
+    document
+}
+
+// Words a reader would see in rendered Markdown: link and image targets, fence lines
+// and markup such as `#`, `*` and table pipes are not counted
+fn count_words(markdown: &str) -> usize {
+    let mut words = 0;
+    for line in markdown.lines() {
+        if line.trim_start().starts_with("```") {
+            continue;
+        }
+        let mut text = String::with_capacity(line.len());
+        let mut rest = line;
+        // Keep `[text]` and `![alt]`, drop the `(target)` after them
+        while let Some(start) = rest.find("](") {
+            text.push_str(&rest[..start]);
+            rest = match rest[start..].find(')') {
+                Some(end) => &rest[start + end + 1..],
+                None => "",
+            };
+        }
+        text.push_str(rest);
+        words += text
+            .split_whitespace()
+            .filter(|word| word.chars().any(char::is_alphanumeric))
+            .count();
+    }
+    words
+}
+
+// A --format text page: its title on the first line, then the text, no frontmatter;
+// a last line names the crawler version that wrote it
+fn render_text(page: &PageResult) -> String {
+    let mut document = match &page.title {
+        Some(title) => format!("{}\n\n{}", title, page.markdown),
+        None => page.markdown.clone(),
+    };
+    if !document.is_empty() && !document.ends_with('\n') {
+        document.push('\n');
+    }
+    document.push_str(&format!("\nGenerated by {}\n", GENERATOR));
+    document
+}
+
+// A --format html-clean page: a minimal document naming the page's title, source and
+// the crawler version that wrote it, around its cleaned content
+fn render_clean_html(page: &PageResult) -> String {
+    let mut document = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    document.push_str(&format!(
+        "<meta name=\"generator\" content=\"{}\">\n",
+        escape_html(GENERATOR)
+    ));
+    if let Some(title) = &page.title {
+        document.push_str(&format!("<title>{}</title>\n", escape_html(title)));
+    }
+    document.push_str(&format!(
+        "<link rel=\"canonical\" href=\"{}\">\n</head>\n<body>\n",
+        escape_html(&page.final_url)
+    ));
+    document.push_str(&page.markdown);
+    document.push_str("</body>\n</html>\n");
+    document
+}
+
+// Pages with less visible text than this are suspected of being rendered by scripts
+const CLIENT_RENDERED_TEXT_BYTES: usize = 200;
+
+// Heuristic for single-page apps: scripts make up most of the page and almost no text,
+// going by the `text` the page was converted to
+fn looks_client_rendered(html: &str, text: &str) -> bool {
+    let lower = html.to_ascii_lowercase();
+    let mut script_bytes = 0;
+    let mut external_script = false;
+    for (start, _) in lower.match_indices("<script") {
+        let script = &lower[start..];
+        let open_end = script.find('>').unwrap_or(script.len());
+        external_script |= script[..open_end].contains("src=");
+        script_bytes += script.find("</script").unwrap_or(script.len());
+    }
+    let has_bundle = external_script || script_bytes * 2 > html.len();
+    has_bundle && text.trim().len() < CLIENT_RENDERED_TEXT_BYTES
+}
+
+/// Choices that change how HTML is converted
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MarkdownOptions {
+    /// Skip site chrome repeated on every page
+    pub strip_boilerplate: bool,
+    /// Emit reading text with no Markdown syntax: no heading markers, emphasis, link
+    /// targets, list bullets, quote markers or code fences
+    pub plain_text: bool,
+    /// Start the Markdown with a linked table of contents of the page's headings
+    pub toc: bool,
+    /// Levels every heading is demoted by, stopping at h6: h1 becomes h2 with 1
+    pub heading_offset: u8,
+    /// Write `[text](javascript:void(0))`, `[text]()` and `[text](#)` as they are
+    /// rather than as the bare text
+    pub keep_dead_links: bool,
+}
+
+/// Step 7: Convert HTML to Markdown
+pub fn html_to_markdown(html: &str, options: MarkdownOptions) -> String {
+    debug!(
+        "Converting HTML to Markdown, input length: {} bytes",
+        html.len()
+    );
+    let mut markdown = String::new();
+    // The block element being filled (heading, paragraph, list item), if any,
+    // and the inline Markdown collected for it so far
+    let mut block: Option<String> = None;
+    let mut inline = String::new();
+    let mut frames: Vec<InlineFrame> = Vec::new();
+    // One entry per open list: the item counter for <ol>, None for <ul>
+    let mut list_stack: Vec<Option<usize>> = Vec::new();
+    // Number of <blockquote> elements open; every line emitted inside gets one '>' each
+    let mut quote_depth = 0;
+    // Of those, the ones opened inside the current list item or definition, whose
+    // markers go into the item's own text so the quote is indented under it
+    let mut item_quote_depth: usize = 0;
+    let mut current_content = String::new();
+    // Byte offset into `html`; only ever moved to a char boundary
+    let mut i = 0;
+
+    while i < html.len() {
+        if html.as_bytes()[i] != b'<' {
+            let next = next_tag_start(html, i);
+            current_content.push_str(&html[i..next]);
+            i = next;
+            continue;
+        }
+        // Comments and CDATA are never shown, even when they hold '>' or whole tags
+        if let Some(end) = hidden_section_end(html, i) {
+            i = end;
+            continue;
+        }
+        // A '<' that does not open a complete tag is kept as literal text
+        let Some((tag, attrs, end)) = scan_tag(html, i) else {
+            current_content.push('<');
+            i += 1;
+            continue;
+        };
+
+        // Process previous content
+        push_inline(
+            &mut frames,
+            &mut inline,
+            &collapse_whitespace(&current_content),
+        );
+        current_content.clear();
+        i = end;
+
+        if let Some(name) = tag.strip_prefix('/') {
+            match name {
+                _ if is_inline_tag(name) => close_inline_until(&mut frames, &mut inline, name),
+                _ if is_transparent_tag(name) => {}
+                "ul" | "ol" => {
+                    flush_block(
+                        &mut markdown,
+                        &mut block,
+                        &mut frames,
+                        &mut inline,
+                        &list_stack,
+                        quote_depth,
+                    );
+                    list_stack.pop();
+                    // A blank line ends the outermost list; without one the next block
+                    // would continue its last item
+                    if list_stack.is_empty() {
+                        separate_block(&mut markdown, quote_depth);
+                    }
+                }
+                "dl" => {
+                    flush_block(
+                        &mut markdown,
+                        &mut block,
+                        &mut frames,
+                        &mut inline,
+                        &list_stack,
+                        quote_depth,
+                    );
+                    separate_block(&mut markdown, quote_depth);
+                }
+                // A paragraph or quote inside a list item continues the item below it
+                "p" | "blockquote" if matches!(block.as_deref(), Some("li" | "dt" | "dd")) => {
+                    if name == "blockquote" {
+                        item_quote_depth = item_quote_depth.saturating_sub(1);
+                    }
+                    push_item_break(&mut frames, &mut inline, item_quote_depth, options, true);
+                }
+                "blockquote" if options.plain_text => flush_block(
+                    &mut markdown,
+                    &mut block,
+                    &mut frames,
+                    &mut inline,
+                    &list_stack,
+                    quote_depth,
+                ),
+                "blockquote" => {
+                    flush_block(
+                        &mut markdown,
+                        &mut block,
+                        &mut frames,
+                        &mut inline,
+                        &list_stack,
+                        quote_depth,
+                    );
+                    // The separator after the quote's last block is not needed inside it
+                    let separator = format!("{}\n", quote_prefix(quote_depth).trim_end());
+                    if quote_depth > 0 && markdown.ends_with(&separator) {
+                        markdown.truncate(markdown.len() - separator.len());
+                    }
+                    quote_depth = quote_depth.saturating_sub(1);
+                    push_quoted(&mut markdown, "\n", quote_depth);
+                }
+                // Wrappers inside a link (<a><figure><img></figure></a>) must not break it
+                _ if is_link_wrapper(&frames, name) => {}
+                _ if is_section_tag(name)
+                    && matches!(block.as_deref(), Some("li" | "dt" | "dd")) =>
+                {
+                    push_word_break(&mut frames, &mut inline);
+                }
+                _ if is_section_tag(name) && list_stack.is_empty() => {
+                    flush_block(
+                        &mut markdown,
+                        &mut block,
+                        &mut frames,
+                        &mut inline,
+                        &list_stack,
+                        quote_depth,
+                    );
+                    separate_block(&mut markdown, quote_depth);
+                }
+                _ => flush_block(
+                    &mut markdown,
+                    &mut block,
+                    &mut frames,
+                    &mut inline,
+                    &list_stack,
+                    quote_depth,
+                ),
+            }
+            continue;
+        }
+        if attrs.trim_end().ends_with('/') && !matches!(tag.as_str(), "br" | "hr" | "img") {
+            // <tag/> opens and immediately closes the element
+            continue;
+        }
+
+        match tag.as_str() {
+            // Plain text keeps the words of emphasis and links, not their markup
+            "strong" | "b" | "em" | "i" | "code" | "a" if options.plain_text => {}
+            "strong" | "b" | "em" | "i" | "code" | "a" => {
+                let href = if tag == "a" {
+                    extract_attribute(&attrs, "href")
+                } else {
+                    None
+                };
+                let text_only =
+                    !options.keep_dead_links && href.as_deref().is_some_and(is_dead_link);
+                frames.push(InlineFrame {
+                    tag,
+                    href,
+                    text_only,
+                    content: String::new(),
+                });
+            }
+            _ if is_transparent_tag(&tag) => {}
+            // A paragraph inside a list item or definition stays part of it
+            "p" if matches!(block.as_deref(), Some("li" | "dt" | "dd")) => {}
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "p" => {
+                flush_block(
+                    &mut markdown,
+                    &mut block,
+                    &mut frames,
+                    &mut inline,
+                    &list_stack,
+                    quote_depth,
+                );
+                block = block_kind(tag, options);
+            }
+            "blockquote" if matches!(block.as_deref(), Some("li" | "dt" | "dd")) => {
+                // The blank line above the quote is still outside it
+                push_item_break(&mut frames, &mut inline, item_quote_depth, options, true);
+                item_quote_depth += 1;
+                if !options.plain_text {
+                    push_inline(&mut frames, &mut inline, &quote_prefix(1));
+                }
+            }
+            "blockquote" if options.plain_text => flush_block(
+                &mut markdown,
+                &mut block,
+                &mut frames,
+                &mut inline,
+                &list_stack,
+                quote_depth,
+            ),
+            "blockquote" => {
+                flush_block(
+                    &mut markdown,
+                    &mut block,
+                    &mut frames,
+                    &mut inline,
+                    &list_stack,
+                    quote_depth,
+                );
+                quote_depth += 1;
+            }
+            "hr" => {
+                flush_block(
+                    &mut markdown,
+                    &mut block,
+                    &mut frames,
+                    &mut inline,
+                    &list_stack,
+                    quote_depth,
+                );
+                separate_block(&mut markdown, quote_depth);
+                if !options.plain_text {
+                    push_quoted(&mut markdown, "---\n\n", quote_depth);
+                }
+            }
+            "dt" | "dd" => {
+                flush_block(
+                    &mut markdown,
+                    &mut block,
+                    &mut frames,
+                    &mut inline,
+                    &list_stack,
+                    quote_depth,
+                );
+                // A blank line ends the previous term's descriptions
+                if tag == "dt" {
+                    separate_block(&mut markdown, quote_depth);
+                }
+                item_quote_depth = 0;
+                block = block_kind(tag, options);
+            }
+            "li" => {
+                flush_block(
+                    &mut markdown,
+                    &mut block,
+                    &mut frames,
+                    &mut inline,
+                    &list_stack,
+                    quote_depth,
+                );
+                if let Some(Some(number)) = list_stack.last_mut() {
+                    *number += 1;
+                }
+                item_quote_depth = 0;
+                block = block_kind(tag, options);
+            }
+            // Discard the body unparsed: scripts can contain a stray '<'
+            "script" | "style" | "noscript" | "title" => {
+                i = raw_text_end(html, i, &tag).1;
+            }
+            "nav" | "header" | "footer" | "aside" if options.strip_boilerplate => {
+                flush_block(
+                    &mut markdown,
+                    &mut block,
+                    &mut frames,
+                    &mut inline,
+                    &list_stack,
+                    quote_depth,
+                );
+                i = raw_text_end(html, i, &tag).1;
+            }
+            // Preformatted text keeps its whitespace; nested markup is dropped
+            "pre" => {
+                flush_block(
+                    &mut markdown,
+                    &mut block,
+                    &mut frames,
+                    &mut inline,
+                    &list_stack,
+                    quote_depth,
+                );
+                let (close, end) = raw_text_end(html, i, &tag);
+                let inner = &html[i..close];
+                i = end;
+                let code = if options.plain_text {
+                    format!("{}\n\n", preformatted_text(inner))
+                } else {
+                    code_block(&attrs, inner)
+                };
+                push_quoted(&mut markdown, &code, quote_depth);
+            }
+            "table" => {
+                flush_block(
+                    &mut markdown,
+                    &mut block,
+                    &mut frames,
+                    &mut inline,
+                    &list_stack,
+                    quote_depth,
+                );
+                let (close, end) = raw_text_end(html, i, &tag);
+                let inner = &html[i..close];
+                i = end;
+                let table = if options.plain_text {
+                    table_to_text(inner)
+                } else {
+                    table_to_markdown(inner)
+                };
+                push_quoted(&mut markdown, &format!("\n{}", table), quote_depth);
+            }
+            // Void element: emit straight from its attributes, there is no closing tag
+            "img" => {
+                let src = extract_attribute(&attrs, "src").unwrap_or_default();
+                let alt = extract_attribute(&attrs, "alt").unwrap_or_default();
+                let image = if options.plain_text {
+                    alt
+                } else {
+                    format!("![{}]({})", alt, src)
+                };
+                push_inline(&mut frames, &mut inline, &image);
+            }
+            "br" => push_item_break(&mut frames, &mut inline, item_quote_depth, options, false),
+            "ul" | "ol" => {
+                flush_block(
+                    &mut markdown,
+                    &mut block,
+                    &mut frames,
+                    &mut inline,
+                    &list_stack,
+                    quote_depth,
+                );
+                // Only a top-level list needs a blank line to separate it from the text above
+                if list_stack.is_empty() {
+                    push_quoted(&mut markdown, "\n", quote_depth);
+                }
+                list_stack.push(if tag == "ul" { None } else { Some(0) });
+            }
+            // A linked thumbnail keeps its wrappers and caption inside the link text
+            _ if is_link_wrapper(&frames, &tag) => {}
+            // Text in containers such as <div> reads as its own paragraph, except in a
+            // list item or definition, where a blank line would break the list apart
+            _ if is_section_tag(&tag) && matches!(block.as_deref(), Some("li" | "dt" | "dd")) => {
+                push_word_break(&mut frames, &mut inline);
+            }
+            _ if is_section_tag(&tag) && list_stack.is_empty() => {
+                flush_block(
+                    &mut markdown,
+                    &mut block,
+                    &mut frames,
+                    &mut inline,
+                    &list_stack,
+                    quote_depth,
+                );
+                separate_block(&mut markdown, quote_depth);
+            }
+            // Any other element starts a new line of plain text
+            _ => flush_block(
+                &mut markdown,
+                &mut block,
+                &mut frames,
+                &mut inline,
+                &list_stack,
+                quote_depth,
+            ),
+        }
+    }
+
+    // Handle remaining content
+    push_inline(
+        &mut frames,
+        &mut inline,
+        &collapse_whitespace(&current_content),
+    );
+    flush_block(
+        &mut markdown,
+        &mut block,
+        &mut frames,
+        &mut inline,
+        &list_stack,
+        quote_depth,
+    );
+    let mut markdown = normalize_blank_lines(&markdown);
+    if options.toc && !options.plain_text {
+        markdown = with_table_of_contents(&markdown);
+    }
+
+    debug!(
+        "Generated Markdown, output length: {} bytes",
+        markdown.len()
+    );
+    markdown
+}
+
+/// Step 7 for --format html-clean: the page's content as HTML with only structural
+/// elements left (headings, paragraphs, lists, links, images, tables, code) and only
+/// the attributes they need. Scripts, styles and comments go, and with
+/// `strip_boilerplate` so does site chrome, as for Markdown; the text of any other
+/// element is kept without its tags.
+pub fn html_to_clean_html(html: &str, options: MarkdownOptions) -> String {
+    let mut clean = String::with_capacity(html.len() / 2);
+    // Byte offset into `html`; only ever moved to a char boundary
+    let mut i = 0;
+    while i < html.len() {
+        if html.as_bytes()[i] != b'<' {
+            let next = next_tag_start(html, i);
+            clean.push_str(&html[i..next]);
+            i = next;
+            continue;
+        }
+        if let Some(end) = hidden_section_end(html, i) {
+            i = end;
+            continue;
+        }
+        let Some((tag, attrs, end)) = scan_tag(html, i) else {
+            clean.push_str("&lt;");
+            i += 1;
+            continue;
+        };
+        i = end;
+        match tag.as_str() {
+            // Discard the body unparsed, as html_to_markdown does
+            "script" | "style" | "noscript" | "title" => i = raw_text_end(html, i, &tag).1,
+            "nav" | "header" | "footer" | "aside" if options.strip_boilerplate => {
+                i = raw_text_end(html, i, &tag).1;
+            }
+            _ => {
+                let (name, closing) = match tag.strip_prefix('/') {
+                    Some(name) => (name, true),
+                    None => (tag.as_str(), false),
+                };
+                let Some(allowed) = clean_html_attributes(name) else {
+                    continue;
+                };
+                if closing {
+                    if !is_void_element(name) {
+                        clean.push_str(&format!("</{}>", name));
+                    }
+                    continue;
+                }
+                clean.push('<');
+                clean.push_str(name);
+                for attribute in allowed {
+                    let Some(value) = extract_attribute(&attrs, attribute) else {
+                        continue;
+                    };
+                    if matches!(*attribute, "href" | "src") && !is_safe_url(&value, name == "img") {
+                        continue;
+                    }
+                    clean.push_str(&format!(" {}=\"{}\"", attribute, escape_html(&value)));
+                }
+                clean.push('>');
+            }
+        }
+    }
+    normalize_clean_html(&clean)
+}
+
+// Clean HTML's counterpart to normalize_blank_lines: trailing whitespace goes and runs
+// of blank lines become one, except inside <pre>, whose text is kept exactly
+fn normalize_clean_html(html: &str) -> String {
+    let mut normalized = String::with_capacity(html.len());
+    let mut pre_depth = 0usize;
+    let mut blank = false;
+    for line in html.split_inclusive('\n') {
+        let starts_in_pre = pre_depth > 0;
+        // Only tags contain '<', so "<pre>" and "</pre>" in the text are always tags
+        let mut rest = line;
+        while let Some(end) = rest.find("pre>") {
+            if rest[..end].ends_with("</") {
+                pre_depth = pre_depth.saturating_sub(1);
+            } else if rest[..end].ends_with('<') {
+                pre_depth += 1;
+            }
+            rest = &rest[end + "pre>".len()..];
+        }
+        if starts_in_pre {
+            normalized.push_str(line);
+            continue;
+        }
+        if line.trim().is_empty() {
+            blank = !normalized.is_empty();
+            continue;
+        }
+        if blank {
+            normalized.push('\n');
+            blank = false;
+        }
+        // Whitespace ending a line that opens a <pre> is already part of its text
+        if pre_depth > 0 {
+            normalized.push_str(line);
+        } else {
+            normalized.push_str(line.trim_end());
+            normalized.push('\n');
+        }
+    }
+    if !normalized.is_empty() && !normalized.ends_with('\n') {
+        normalized.push('\n');
+    }
+    normalized
+}
+
+// The attributes a tag keeps in clean HTML, or None for a tag that is dropped
+fn clean_html_attributes(tag: &str) -> Option<&'static [&'static str]> {
+    match tag {
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "p" | "br" | "hr" | "blockquote" | "ul"
+        | "li" | "dl" | "dt" | "dd" | "pre" | "code" | "strong" | "b" | "em" | "i" | "table"
+        | "caption" | "thead" | "tbody" | "tfoot" | "tr" | "figure" | "figcaption" => Some(&[]),
+        "ol" => Some(&["start"]),
+        "a" => Some(&["href", "title"]),
+        "img" => Some(&["src", "alt", "title", "width", "height"]),
+        "th" | "td" => Some(&["colspan", "rowspan"]),
+        _ => None,
+    }
+}
+
+// Elements that never have a closing tag
+fn is_void_element(tag: &str) -> bool {
+    matches!(tag, "br" | "hr" | "img")
+}
+
+// Whether a link or image target is safe to keep: scripts and other schemes that run
+// code are dropped, and data: URIs are only kept for images
+fn is_safe_url(url: &str, is_image: bool) -> bool {
+    let url = url.trim().to_ascii_lowercase();
+    let scheme_end = url
+        .find([':', '/', '?', '#'])
+        .filter(|&end| url[end..].starts_with(':'));
+    match scheme_end.map(|end| &url[..end]) {
+        None | Some("http" | "https" | "mailto" | "tel") => true,
+        Some("data") => is_image && url.starts_with("data:image/"),
+        Some(_) => false,
+    }
+}
+
+// The text of clean HTML, with every tag replaced by a space
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+// Escape text for use inside HTML, including double-quoted attribute values
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// The block a heading, paragraph, list item or definition opens. flush_block renders
+// "p" as a paragraph and no block as a line, which is all plain text needs of them.
+fn block_kind(tag: String, options: MarkdownOptions) -> Option<String> {
+    if !options.plain_text {
+        let level = tag
+            .strip_prefix('h')
+            .and_then(|level| level.parse::<u8>().ok());
+        return match level {
+            Some(level) if options.heading_offset > 0 => Some(format!(
+                "h{}",
+                level.saturating_add(options.heading_offset).min(6)
+            )),
+            _ => Some(tag),
+        };
+    }
+    match tag.as_str() {
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "p" => Some("p".to_string()),
+        _ => None,
+    }
+}
+
+// Tidy the emitted Markdown: trailing whitespace goes, runs of blank lines become one
+// and the document ends with exactly one newline. Fenced code is left untouched.
+fn normalize_blank_lines(markdown: &str) -> String {
+    let mut normalized = String::with_capacity(markdown.len());
+    let mut fence: Option<String> = None;
+    // The blank line to write before the next content; of a run, the one with the fewest
+    // quote markers, so a quote's end is kept
+    let mut blank: Option<&str> = None;
+    for line in markdown.lines() {
+        let content = line.trim_start_matches(['>', ' ']);
+        if let Some(open) = &fence {
+            if content.trim_end() == open {
+                fence = None;
+            }
+            normalized.push_str(line);
+            normalized.push('\n');
+            continue;
+        }
+        let line = line.trim_end();
+        if content.trim_end().is_empty() {
+            if !normalized.is_empty() && blank.is_none_or(|kept| line.len() < kept.len()) {
+                blank = Some(line);
+            }
+            continue;
+        }
+        if let Some(blank) = blank.take() {
+            normalized.push_str(blank);
+            normalized.push('\n');
+        }
+        if content.starts_with("```") {
+            fence = Some(content.chars().take_while(|c| *c == '`').collect());
+        }
+        normalized.push_str(line);
+        normalized.push('\n');
+    }
+    normalized
+}
+
+// Put a nested list of links to the document's headings in front of it, indented by
+// level and pointing at the anchors GitHub-style renderers give each heading
+fn with_table_of_contents(markdown: &str) -> String {
+    let mut headings = Vec::new();
+    let mut fence: Option<String> = None;
+    for line in markdown.lines() {
+        if let Some(open) = &fence {
+            if line.trim_end() == open {
+                fence = None;
+            }
+            continue;
+        }
+        if line.starts_with("```") {
+            fence = Some(line.chars().take_while(|c| *c == '`').collect());
+            continue;
+        }
+        let level = line.chars().take_while(|c| *c == '#').count();
+        if let Some(text) = line[level..].strip_prefix(' ')
+            && (1..=6).contains(&level)
+        {
+            headings.push((level, heading_text(text)));
+        }
+    }
+    let Some(top) = headings.iter().map(|(level, _)| *level).min() else {
+        return markdown.to_string();
+    };
+    let mut slugs = HashSet::new();
+    let mut toc = String::new();
+    for (level, text) in &headings {
+        toc.push_str(&format!(
+            "{}- [{}](#{})\n",
+            "    ".repeat(level - top),
+            text,
+            unique_slug(&heading_slug(text), &mut slugs)
+        ));
+    }
+    format!("{}\n{}", toc, markdown)
+}
+
+// A Markdown link or image, capturing its text
+static MARKDOWN_LINK: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r"!?\[([^\]]*)\]\([^)]*\)").expect("link pattern is valid"));
+
+// A heading's text without its inline Markdown: links and images become their text,
+// and emphasis and code markers go
+fn heading_text(markdown: &str) -> String {
+    MARKDOWN_LINK
+        .replace_all(markdown, "$1")
+        .replace(['*', '`'], "")
+        .trim()
+        .to_string()
+}
+
+// GitHub's anchor for a heading: lowercase, punctuation dropped, spaces as hyphens
+fn heading_slug(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+        .map(|c| if c == ' ' { '-' } else { c })
+        .collect()
+}
+
+// A repeated slug gets -1, -2 and so on, as GitHub numbers repeated headings
+fn unique_slug(slug: &str, used: &mut HashSet<String>) -> String {
+    let mut unique = slug.to_string();
+    let mut n = 0;
+    while !used.insert(unique.clone()) {
+        n += 1;
+        unique = format!("{}-{}", slug, n);
+    }
+    unique
+}
+
+// An open inline element and the Markdown of its content so far
+struct InlineFrame {
+    tag: String,
+    href: Option<String>,
+    // A link whose target leads nowhere, written as its text alone
+    text_only: bool,
+    content: String,
+}
+
+// A link target that goes nowhere when followed: a script, nothing, or the page top
+fn is_dead_link(href: &str) -> bool {
+    let href = href.trim();
+    href.is_empty()
+        || href == "#"
+        || href
+            .get(.."javascript:".len())
+            .is_some_and(|scheme| scheme.eq_ignore_ascii_case("javascript:"))
+}
+
+// Elements that wrap their content in Markdown emphasis, code or link syntax
+fn is_inline_tag(tag: &str) -> bool {
+    matches!(tag, "strong" | "b" | "em" | "i" | "code" | "a")
+}
+
+// Generic containers of page content, which start and end a paragraph of their own
+fn is_section_tag(tag: &str) -> bool {
+    matches!(
+        tag,
+        "div"
+            | "section"
+            | "article"
+            | "main"
+            | "header"
+            | "footer"
+            | "aside"
+            | "nav"
+            | "figure"
+            | "figcaption"
+            | "details"
+            | "summary"
+            | "address"
+    )
+}
+
+// Inline elements without a Markdown form; their text simply flows through
+fn is_transparent_tag(tag: &str) -> bool {
+    matches!(
+        tag,
+        "span"
+            | "u"
+            | "small"
+            | "sub"
+            | "sup"
+            | "mark"
+            | "abbr"
+            | "cite"
+            | "time"
+            | "label"
+            | "font"
+            | "q"
+            | "s"
+            | "del"
+            | "ins"
+            | "kbd"
+            | "var"
+            | "samp"
+    )
+}
+
+// Replace runs of HTML whitespace with one space and decode entities
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut in_space = false;
+    for c in text.chars() {
+        if c.is_ascii_whitespace() {
+            if !in_space {
+                collapsed.push(' ');
+            }
+            in_space = true;
+        } else {
+            collapsed.push(c);
+            in_space = false;
+        }
+    }
+    decode_entities(&collapsed)
+}
+
+// Whether `tag` is one of the wrappers a linked thumbnail puts around its image,
+// inside an open <a>, so it stays part of the link instead of breaking it
+fn is_link_wrapper(frames: &[InlineFrame], tag: &str) -> bool {
+    matches!(tag, "figure" | "figcaption" | "picture" | "source" | "div")
+        && frames.iter().any(|frame| frame.tag == "a")
+}
+
+// Append Markdown to the innermost open inline element, or to the block itself
+fn push_inline(frames: &mut [InlineFrame], inline: &mut String, text: &str) {
+    match frames.last_mut() {
+        Some(frame) => frame.content.push_str(text),
+        None => inline.push_str(text),
+    }
+}
+
+// Start a new line, or with `paragraph` a new paragraph, in a block's text. Inside
+// `item_quote_depth` blockquotes opened within a list item, each line repeats their
+// markers, as the item's lines are indented only once the item is emitted.
+fn push_item_break(
+    frames: &mut [InlineFrame],
+    inline: &mut String,
+    item_quote_depth: usize,
+    options: MarkdownOptions,
+    paragraph: bool,
+) {
+    let prefix = if options.plain_text {
+        String::new()
+    } else {
+        quote_prefix(item_quote_depth)
+    };
+    let separator = if paragraph {
+        format!("\n{}\n{}", prefix.trim_end(), prefix)
+    } else {
+        format!("\n{}", prefix)
+    };
+    push_inline(frames, inline, &separator);
+}
+
+// Keep the words either side of a container boundary apart, with a single space
+fn push_word_break(frames: &mut [InlineFrame], inline: &mut String) {
+    let text = frames
+        .last()
+        .map_or(inline.as_str(), |frame| &frame.content);
+    if !text.is_empty() && !text.ends_with(' ') {
+        push_inline(frames, inline, " ");
+    }
+}
+
+// Close the innermost inline element, wrapping its content in Markdown syntax
+fn close_inline(frames: &mut Vec<InlineFrame>, inline: &mut String) {
+    let Some(frame) = frames.pop() else {
+        return;
+    };
+    // Markdown markers must hug the text, so surrounding spaces move outside them
+    let text = frame.content.trim();
+    let leading = if frame.content.starts_with(' ') {
+        " "
+    } else {
+        ""
+    };
+    let trailing = if text.is_empty() || !frame.content.ends_with(' ') {
+        ""
+    } else {
+        " "
+    };
+    let wrapped = match frame.tag.as_str() {
+        _ if text.is_empty() && frame.tag != "a" => String::new(),
+        "strong" | "b" => format!("**{}**", text),
+        "em" | "i" => format!("*{}*", text),
+        "code" => format!("`{}`", text),
+        _ if frame.text_only => text.to_string(),
+        // Fall back to the link text when the anchor has no href
+        _ => {
+            let target = frame.href.as_deref().unwrap_or(text);
+            // Phone numbers are often written with spaces, which would end the target
+            let tel;
+            let target = match target.get(.."tel:".len()) {
+                Some(scheme) if scheme.eq_ignore_ascii_case("tel:") => {
+                    tel = target.split_whitespace().collect::<String>();
+                    tel.as_str()
+                }
+                _ => target,
+            };
+            if text.is_empty() && target.is_empty() {
+                String::new()
+            } else {
+                format!("[{}]({})", text, target)
+            }
+        }
+    };
+    push_inline(
+        frames,
+        inline,
+        &format!("{}{}{}", leading, wrapped, trailing),
+    );
+}
+
+// Close open inline elements up to and including the innermost `tag`
+fn close_inline_until(frames: &mut Vec<InlineFrame>, inline: &mut String, tag: &str) {
+    // A closing tag that was never opened is ignored
+    let Some(depth) = frames.iter().rposition(|frame| frame.tag == tag) else {
+        return;
+    };
+    while frames.len() > depth {
+        close_inline(frames, inline);
+    }
+}
+
+// Emit the finished block element and start over with no block open
+fn flush_block(
+    markdown: &mut String,
+    block: &mut Option<String>,
+    frames: &mut Vec<InlineFrame>,
+    inline: &mut String,
+    list_stack: &[Option<usize>],
+    quote_depth: usize,
+) {
+    while !frames.is_empty() {
+        close_inline(frames, inline);
+    }
+    let text = inline
+        .split('\n')
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join("\n");
+    let text = text.trim();
+    if !text.is_empty() {
+        let rendered = match block.as_deref() {
+            Some("h1") => format!("# {}\n\n", text),
+            Some("h2") => format!("## {}\n\n", text),
+            Some("h3") => format!("### {}\n\n", text),
+            Some("h4") => format!("#### {}\n\n", text),
+            Some("h5") => format!("##### {}\n\n", text),
+            Some("h6") => format!("###### {}\n\n", text),
+            Some("p") => format!("{}\n\n", text),
+            Some("dt") => format!("**{}**\n", text),
+            Some("dd") => format!("    {}\n", text.replace('\n', "\n    ")),
+            Some("li") => {
+                let indent = "    ".repeat(list_stack.len().saturating_sub(1));
+                // Later lines and paragraphs of the item line up under its text
+                let text = text.replace('\n', &format!("\n{}    ", indent));
+                match list_stack.last() {
+                    Some(Some(number)) => format!("{}{}. {}\n", indent, number, text),
+                    _ => format!("{}- {}\n", indent, text),
+                }
+            }
+            _ => format!("{}\n", text),
+        };
+        push_quoted(markdown, &rendered, quote_depth);
+    }
+    inline.clear();
+    *block = None;
+}
+
+// End the Markdown so far with a blank line unless it already does, so the next block
+// is not read as a continuation of the last one
+fn separate_block(markdown: &mut String, quote_depth: usize) {
+    let last_line = markdown
+        .strip_suffix('\n')
+        .map(|text| text.rsplit('\n').next().unwrap_or_default());
+    if let Some(line) = last_line
+        && !line.trim_matches(['>', ' ']).is_empty()
+    {
+        push_quoted(markdown, "\n", quote_depth);
+    }
+}
+
+// Marker put in front of each line inside `depth` nested blockquotes
+fn quote_prefix(depth: usize) -> String {
+    "> ".repeat(depth)
+}
+
+// Append Markdown lines, each prefixed with the quote markers for `depth`
+fn push_quoted(markdown: &mut String, text: &str, depth: usize) {
+    if depth == 0 {
+        markdown.push_str(text);
+        return;
+    }
+    let prefix = quote_prefix(depth);
+    for line in text.split_inclusive('\n') {
+        // Blank lines keep the markers so the quote is not broken in two
+        if line.trim().is_empty() {
+            markdown.push_str(prefix.trim_end());
+        } else {
+            markdown.push_str(&prefix);
+        }
+        markdown.push_str(line);
+    }
+}
+
+// Lowercased name, raw attributes and the byte offset just past '>' of the tag
+// opening at `start`, or None when the '<' does not begin a terminated tag
+fn scan_tag(html: &str, start: usize) -> Option<(String, String, usize)> {
+    let bytes = html.as_bytes();
+    let first = *bytes.get(start + 1)?;
+    if !(first.is_ascii_alphabetic() || matches!(first, b'/' | b'!' | b'?')) {
+        return None;
+    }
+
+    // Every delimiter is ASCII, so each offset below falls on a char boundary
+    let name_start = if first == b'/' { start + 2 } else { start + 1 };
+    let name_end = bytes[name_start..]
+        .iter()
+        .position(|b| b.is_ascii_whitespace() || matches!(b, b'>' | b'/' | b'<'))
+        .map_or(bytes.len(), |len| name_start + len);
+    let tag = html[start + 1..name_end].to_ascii_lowercase();
+
+    let attrs_end = name_end
+        + bytes[name_end..]
+            .iter()
+            .position(|b| matches!(b, b'>' | b'<'))?;
+    let attrs = html[name_end..attrs_end].to_string();
+    match bytes[attrs_end] {
+        b'>' => Some((tag, attrs, attrs_end + 1)),
+        // A new tag begins before this one was closed: end it here
+        _ => Some((tag, attrs, attrs_end)),
+    }
+}
+
+// The offset just past a <!-- comment --> or <![CDATA[ section ]]> opening at `start`,
+// or the end of the text when it is never closed
+fn hidden_section_end(html: &str, start: usize) -> Option<usize> {
+    let (from, close) = if html[start..].starts_with("<!--") {
+        // From the "--" of the opener, so "<!-->" and "<!--->" end where they begin
+        (start + "<!".len(), "-->")
+    } else if html[start..].starts_with("<![CDATA[") {
+        (start + "<![CDATA[".len(), "]]>")
+    } else {
+        return None;
+    };
+    Some(
+        html[from..]
+            .find(close)
+            .map_or(html.len(), |end| from + end + close.len()),
+    )
+}
+
+// Byte offset of the next '<' at or after `from`, or the end of the text
+fn next_tag_start(html: &str, from: usize) -> usize {
+    html.as_bytes()[from..]
+        .iter()
+        .position(|&b| b == b'<')
+        .map_or(html.len(), |len| from + len)
+}
+
+// End of a raw-text element's content and the offset just past its closing tag
+fn raw_text_end(html: &str, from: usize, tag: &str) -> (usize, usize) {
+    match find_closing_tag(html, from, tag) {
+        Some(close) => {
+            let end = html.as_bytes()[close..]
+                .iter()
+                .position(|&b| b == b'>')
+                .map_or(html.len(), |end| close + end + 1);
+            (close, end)
+        }
+        None => (html.len(), html.len()),
+    }
+}
+
+// Fenced code block for the inner HTML of a <pre>, tagged with its language class
+fn code_block(pre_attrs: &str, inner: &str) -> String {
+    // Highlighters usually put the class on a <code> wrapped by the <pre>
+    let code_attrs = inner
+        .trim_start()
+        .strip_prefix("<code")
+        .and_then(|rest| rest.split_once('>'))
+        .map_or("", |(attrs, _)| attrs);
+    let language = [pre_attrs, code_attrs]
+        .into_iter()
+        .filter_map(|attrs| extract_attribute(attrs, "class"))
+        .find_map(|class| {
+            class.split_whitespace().find_map(|name| {
+                name.strip_prefix("language-")
+                    .or_else(|| name.strip_prefix("lang-"))
+                    .map(str::to_string)
+            })
+        })
+        .unwrap_or_default();
+
+    let code = preformatted_text(inner);
+
+    // Use a longer fence than any backtick run inside the code
+    let longest_run = code.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest_run.max(2) + 1);
+    format!("{}{}\n{}\n{}\n\n", fence, language, code, fence)
+}
+
+// The text of a <pre> element's inner HTML, whitespace kept and markup dropped
+fn preformatted_text(inner: &str) -> String {
+    let mut text = String::with_capacity(inner.len());
+    let mut in_markup = false;
+    for c in inner.chars() {
+        match c {
+            '<' => in_markup = true,
+            '>' if in_markup => in_markup = false,
+            c if !in_markup => text.push(c),
+            _ => {}
+        }
+    }
+    decode_entities(&text)
+        .trim_matches(|c| c == '\n' || c == '\r')
+        .to_string()
+}
+
+// Pipe table for the inner HTML of a <table>; the first row always becomes the header
+fn table_to_markdown(html: &str) -> String {
+    let rows = table_rows(html);
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    if columns == 0 {
+        return String::new();
+    }
+    let mut table = String::new();
+    for (index, cells) in rows.iter().enumerate() {
+        table.push('|');
+        for column in 0..columns {
+            let text = cells.get(column).map_or("", String::as_str);
+            // Escape pipes so they don't split the cell
+            table.push_str(&format!(" {} |", text.replace('|', "\\|")));
+        }
+        table.push('\n');
+        if index == 0 {
+            table.push_str(&"| --- ".repeat(columns));
+            table.push_str("|\n");
+        }
+    }
+    table.push('\n');
+    table
+}
+
+// A table as plain text: one line per row, cells separated by tabs
+fn table_to_text(html: &str) -> String {
+    let rows = table_rows(html);
+    if rows.is_empty() {
+        return String::new();
+    }
+    let mut table = String::new();
+    for cells in rows {
+        table.push_str(&cells.join("\t"));
+        table.push('\n');
+    }
+    table.push('\n');
+    table
+}
+
+// Most columns one cell's colspan can cover, however many it asks for
+const MAX_COLSPAN: usize = 100;
+
+// The text of each cell of each row in the inner HTML of a <table>. A cell spanning
+// several columns is followed by empty ones, so later cells stay in their columns.
+fn table_rows(html: &str) -> Vec<Vec<String>> {
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let mut row: Option<Vec<String>> = None;
+    // The open cell's text and how many columns it spans
+    let mut cell: Option<(String, usize)> = None;
+    let mut i = 0;
+
+    while i < html.len() {
+        let scanned = if html.as_bytes()[i] == b'<' {
+            scan_tag(html, i)
+        } else {
+            None
+        };
+        let Some((tag, attrs, end)) = scanned else {
+            // Text up to the next tag, or a '<' that starts none
+            let next = next_tag_start(html, i + 1);
+            if let Some((cell, _)) = &mut cell {
+                cell.push_str(&html[i..next]);
+            }
+            i = next;
+            continue;
+        };
+        i = end;
+
+        match tag.as_str() {
+            "tr" | "/tr" | "td" | "th" | "/td" | "/th" | "/table" => {
+                if let Some((text, span)) = cell.take() {
+                    push_table_cell(row.get_or_insert_with(Vec::new), &text, span);
+                }
+                match tag.as_str() {
+                    "td" | "th" => {
+                        let span = extract_attribute(&attrs, "colspan")
+                            .and_then(|span| span.trim().parse::<usize>().ok())
+                            .unwrap_or(1)
+                            .clamp(1, MAX_COLSPAN);
+                        cell = Some((String::new(), span));
+                    }
+                    "tr" | "/tr" => rows.extend(row.take()),
+                    _ => {}
+                }
+            }
+            // Keep words on either side of a line break apart
+            "br" => {
+                if let Some((cell, _)) = &mut cell {
+                    cell.push(' ');
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some((text, span)) = cell.take() {
+        push_table_cell(row.get_or_insert_with(Vec::new), &text, span);
+    }
+    rows.extend(row.take());
+    rows
+}
+
+// Add a cell's text to its row, then an empty cell for each further column it spans
+fn push_table_cell(row: &mut Vec<String>, text: &str, span: usize) {
+    row.push(table_cell(text));
+    row.extend(std::iter::repeat_n(String::new(), span - 1));
+}
+
+// Cell text on a single line
+fn table_cell(text: &str) -> String {
+    let text = decode_entities(text);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Position of the `</tag` that closes a raw-text element such as <script>
+fn find_closing_tag(html: &str, from: usize, tag: &str) -> Option<usize> {
+    let needle = format!("</{}", tag);
+    let bytes = html.as_bytes();
+    let last_start = bytes.len().checked_sub(needle.len())?;
+    (from..=last_start)
+        .find(|&start| bytes[start..start + needle.len()].eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+// Extract a quoted or bare attribute value from the raw attribute text of a tag
+fn extract_attribute(attrs: &str, name: &str) -> Option<String> {
+    // ASCII lowercasing keeps byte offsets identical to the original text
+    let lower = attrs.to_ascii_lowercase();
+    let mut search_from = 0;
+    while let Some(pos) = lower[search_from..].find(name) {
+        let start = search_from + pos;
+        let end = start + name.len();
+        search_from = end;
+
+        let at_boundary = start == 0 || lower.as_bytes()[start - 1].is_ascii_whitespace();
+        let rest = attrs[end..].trim_start();
+        if !at_boundary || !rest.starts_with('=') {
+            continue;
+        }
+
+        let value = rest[1..].trim_start();
+        return match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..]
+                .find(quote)
+                .map(|close| decode_entities(&value[1..close + 1])),
+            Some(_) => value.split_whitespace().next().map(decode_entities),
+            None => None,
+        };
+    }
+    None
+}
+
+// Text of the page's <title> element with entities decoded and whitespace collapsed
+fn extract_title(html: &str) -> Option<String> {
+    // ASCII lowercasing keeps byte offsets identical to the original text
+    let lower = html.to_ascii_lowercase();
+    let mut search_from = 0;
+    while let Some(pos) = lower[search_from..].find("<title") {
+        let start = search_from + pos + "<title".len();
+        search_from = start;
+        if !lower[start..].starts_with(|c: char| c == '>' || c.is_ascii_whitespace()) {
+            continue;
+        }
+        let open_end = start + lower[start..].find('>')? + 1;
+        let close = open_end + lower[open_end..].find("</title")?;
+        let title = decode_entities(&html[open_end..close]);
+        let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+        return (!title.is_empty()).then_some(title);
+    }
+    None
+}
+
+// Target of the page's <link rel="canonical">, normalized, as long as it stays on the
+// page's host; a canonical on another site is not trusted to name this page
+fn extract_canonical(html: &str, page_url: &str, tracking_params: &[String]) -> Option<String> {
+    let base = Url::parse(page_url).ok()?;
+    let mut i = 0;
+    loop {
+        i = next_tag_start(html, i);
+        if i == html.len() {
+            break;
+        }
+        let Some((tag, attrs, end)) = scan_tag(html, i) else {
+            i += 1;
+            continue;
+        };
+        i = end;
+        match tag.as_str() {
+            "script" | "style" => i = raw_text_end(html, i, &tag).1,
+            // The canonical link belongs in <head>
+            "body" | "/head" => return None,
+            "link" => {
+                let is_canonical = extract_attribute(&attrs, "rel").is_some_and(|rel| {
+                    rel.split_ascii_whitespace()
+                        .any(|token| token.eq_ignore_ascii_case("canonical"))
+                });
+                if !is_canonical {
+                    continue;
+                }
+                let target = extract_attribute(&attrs, "href")
+                    .and_then(|href| base.join(href.trim()).ok())?;
+                if target.host_str() != base.host_str() {
+                    debug!(
+                        "Ignoring canonical {} on {}: it is on another host",
+                        target, page_url
+                    );
+                    return None;
+                }
+                return Some(normalize_url(target.as_str(), tracking_params));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// Directives of the page's <meta name="robots"> tags, where "none" means both
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct MetaRobots {
+    noindex: bool,
+    nofollow: bool,
+}
+
+fn extract_meta_robots(html: &str) -> MetaRobots {
+    let mut robots = MetaRobots::default();
+    let mut i = 0;
+    loop {
+        i = next_tag_start(html, i);
+        if i == html.len() {
+            break;
+        }
+        let Some((tag, attrs, end)) = scan_tag(html, i) else {
+            i += 1;
+            continue;
+        };
+        i = end;
+        match tag.as_str() {
+            "script" | "style" => i = raw_text_end(html, i, &tag).1,
+            "body" | "/head" => break,
+            "meta" => {
+                let is_robots = extract_attribute(&attrs, "name")
+                    .is_some_and(|name| name.trim().eq_ignore_ascii_case("robots"));
+                let Some(content) = extract_attribute(&attrs, "content").filter(|_| is_robots)
+                else {
+                    continue;
+                };
+                for directive in content.split(',').map(str::trim) {
+                    let none = directive.eq_ignore_ascii_case("none");
+                    robots.noindex |= none || directive.eq_ignore_ascii_case("noindex");
+                    robots.nofollow |= none || directive.eq_ignore_ascii_case("nofollow");
+                }
+            }
+            _ => {}
+        }
+    }
+    robots
+}
+
+// Absolute targets of the page's <a href> links that stay on the page's host
+fn extract_links(html: &str, page_url: &str, tracking_params: &[String]) -> Vec<String> {
+    let Ok(base) = Url::parse(page_url) else {
+        return Vec::new();
+    };
+    let mut links = Vec::new();
+    let mut i = 0;
+    loop {
+        i = next_tag_start(html, i);
+        if i == html.len() {
+            break;
+        }
+        let Some((tag, attrs, end)) = scan_tag(html, i) else {
+            i += 1;
+            continue;
+        };
+        i = end;
+        match tag.as_str() {
+            "script" | "style" => i = raw_text_end(html, i, &tag).1,
+            "a" => {
+                let Some(href) = extract_attribute(&attrs, "href") else {
+                    continue;
+                };
+                let Ok(target) = base.join(href.trim()) else {
+                    debug!("Skipping unresolvable link {:?} on {}", href, page_url);
+                    continue;
+                };
+                // Skips mailto:, javascript: and links to other sites
+                if matches!(target.scheme(), "http" | "https")
+                    && target.host_str() == base.host_str()
+                {
+                    links.push(normalize_url(target.as_str(), tracking_params));
+                }
+            }
+            _ => {}
+        }
+    }
+    links.sort();
+    links.dedup();
+    links
+}
+
+// Double-quoted YAML scalar, escaping the characters that would end or break it
+fn yaml_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Decode named and numeric HTML entities, leaving stray ampersands untouched
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+
+        // Entity names are short; anything longer is a literal ampersand
+        let entity = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        match entity.and_then(|name| decode_entity(name).map(|c| (c, name.len()))) {
+            Some((c, len)) => {
+                decoded.push(c);
+                rest = &rest[len + 2..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let code = name.strip_prefix('#')?;
+            let value = match code.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => code.parse().ok()?,
+            };
+            char::from_u32(value)
+        }
+    }
+}
+
+// Save one page's Markdown to its file, `saved_as` under the output directory
+fn save_markdown(
+    saved_as: &Path,
+    markdown: &str,
+    config: &CrawlerConfig,
+) -> Result<(), CrawlerError> {
+    // Create output directory
+    create_dir_all(&config.output_dir)?;
+
+    let file_path = config.output_dir.join(saved_as);
+    if let Some(dir) = file_path.parent() {
+        create_dir_all(dir)?;
+    }
+
+    // Save file
+    write_atomic(&file_path, markdown.as_bytes())?;
+    debug!("Saved Markdown file: {}", file_path.display());
+    // In the format `sha256sum -c` checks, run from the file's own directory
+    if config.checksums {
+        let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
+        let checksum = format!("{}  {}\n", sha256_hex(markdown.as_bytes()), file_name);
+        let mut checksum_path = file_path.into_os_string();
+        checksum_path.push(".sha256");
+        write_atomic(Path::new(&checksum_path), checksum.as_bytes())?;
+    }
+    Ok(())
+}
+
+// Keep the HTML a page was converted from next to its output file, as <name>.html,
+// or <name>.raw.html when the page itself is saved as <name>.html
+fn save_raw_html(saved_as: &Path, html: &str, config: &CrawlerConfig) -> Result<(), CrawlerError> {
+    let mut raw_path = saved_as.with_extension("html");
+    if raw_path == saved_as {
+        raw_path = saved_as.with_extension("raw.html");
+    }
+    let file_path = config.output_dir.join(raw_path);
+    if let Some(dir) = file_path.parent() {
+        create_dir_all(dir)?;
+    }
+    write_atomic(&file_path, html.as_bytes())?;
+    debug!("Saved HTML file: {}", file_path.display());
+    Ok(())
+}
+
+// Between the pages of a --single-file document
+const SINGLE_FILE_SEPARATOR: &str = "\n\n---\n\n";
+
+// Write all pages into one document, each under an H1 with its title and source URL.
+// With `append`, an earlier domain's pages in the file are kept ahead of these.
+fn save_single_file(path: &Path, pages: &[PageResult], append: bool) -> Result<(), CrawlerError> {
+    if append && pages.is_empty() {
+        return Ok(());
+    }
+    let sections: Vec<String> = pages
+        .iter()
+        .map(|page| {
+            let title = page.title.as_deref().unwrap_or(&page.final_url);
+            format!(
+                "# {}\n\nSource: <{}>\n\n{}",
+                title,
+                page.final_url,
+                page.markdown.trim_end()
+            )
+        })
+        .collect();
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        create_dir_all(dir)?;
+    }
+    let mut document = sections.join(SINGLE_FILE_SEPARATOR);
+    document.push('\n');
+    if append {
+        // Add to the end rather than reading the earlier pages back in; the file already
+        // ends with the newline the separator starts with
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if file.metadata()?.len() > 0 {
+            file.write_all(&SINGLE_FILE_SEPARATOR.as_bytes()[1..])?;
+        }
+        file.write_all(document.as_bytes())?;
+    } else {
+        write_atomic(path, document.as_bytes())?;
+    }
+    info!("Wrote {} pages to {}", pages.len(), path.display());
+    Ok(())
+}
+
+// Write to a hidden temporary file beside `path` and rename it into place, so an
+// interrupted write (or an rsync running alongside) never sees a torn file
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), CrawlerError> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let partial = path.with_file_name(format!(".{}.tmp", file_name));
+    fs::write(&partial, contents)?;
+    if let Err(e) = fs::rename(&partial, path) {
+        let _ = fs::remove_file(&partial);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+const IMAGES_DIR: &str = "images";
+
+// Download the images a page's Markdown references and rewrite each `![alt](src)` to
+// the saved copy. Data URIs and images that fail to download keep their original src.
+// `saved_as` is the page's file under the output directory, which decides how deep it
+// sits unless every page goes into the --single-file document.
+async fn localize_images(
+    markdown: &str,
+    page_url: &str,
+    saved_as: &Path,
+    config: &CrawlerConfig,
+    scheduler: &Scheduler,
+) -> String {
+    let Ok(base) = Url::parse(page_url) else {
+        return markdown.to_string();
+    };
+    // JSONL records have no file of their own, so their paths start at the output directory
+    let root = match (&config.single_file, config.format) {
+        (Some(path), _) => single_file_root(path, &config.output_dir),
+        (None, OutputFormat::Markdown | OutputFormat::Text | OutputFormat::HtmlClean) => {
+            relative_root(saved_as)
+        }
+        (None, OutputFormat::Jsonl) => String::new(),
+    };
+    let mut localized = String::with_capacity(markdown.len());
+    let mut saved: HashMap<String, String> = HashMap::new();
+    let mut rest = markdown;
+    while let Some(start) = rest.find("![") {
+        let Some((alt_end, src_end)) = rest[start..].find("](").and_then(|alt_end| {
+            let src_start = start + alt_end + 2;
+            rest[src_start..]
+                .find(')')
+                .map(|len| (start + alt_end, src_start + len))
+        }) else {
+            break;
+        };
+        let src = &rest[alt_end + 2..src_end];
+        localized.push_str(&rest[..alt_end + 2]);
+        if !saved.contains_key(src)
+            && let Some(local) = download_image(&base, src, config, scheduler).await
+        {
+            saved.insert(src.to_string(), format!("{}{}", root, local));
+        }
+        localized.push_str(saved.get(src).map_or(src, String::as_str));
+        localized.push(')');
+        rest = &rest[src_end + 1..];
+    }
+    localized.push_str(rest);
+    localized
+}
+
+// The URLs above each collected page, e.g. https://site.com/docs and
+// https://site.com/docs/guide for https://site.com/docs/guide/intro?lang=en
+fn parent_pages(page_urls: &[String]) -> HashSet<String> {
+    let mut parents = HashSet::new();
+    for url in page_urls {
+        let page = url.split(['?', '#']).next().unwrap_or_default();
+        let Some(path_start) = page
+            .find("://")
+            .and_then(|scheme_end| Some(scheme_end + 3 + page[scheme_end + 3..].find('/')?))
+        else {
+            continue;
+        };
+        let mut end = page.len();
+        while let Some(slash) = page[..end].rfind('/').filter(|slash| *slash > path_start) {
+            parents.insert(page[..slash].to_string());
+            end = slash;
+        }
+    }
+    parents
+}
+
+// Where a page's Markdown (or text) goes, relative to the output directory
+fn markdown_path(url: &str, config: &CrawlerConfig, parent_pages: &HashSet<String>) -> PathBuf {
+    let path = if config.preserve_structure {
+        let page = url.split(['?', '#']).next().unwrap_or_default();
+        url_to_nested_path(url, parent_pages.contains(page))
+    } else {
+        PathBuf::from(url_to_file_name(url))
+    };
+    match (&config.extension, config.format) {
+        (Some(extension), _) => path.with_extension(extension.trim_start_matches('.')),
+        (None, OutputFormat::Text) => path.with_extension("txt"),
+        (None, OutputFormat::HtmlClean) => path.with_extension("html"),
+        (None, _) => path,
+    }
+}
+
+// The "../" that leads from a page's Markdown file back up to the output directory
+fn relative_root(markdown_path: &Path) -> String {
+    "../".repeat(markdown_path.components().count().saturating_sub(1))
+}
+
+// The path that leads from the --single-file document to the output directory, which
+// need not hold it: "../out/" for docs/all.md written with --out out
+fn single_file_root(single_file: &Path, output_dir: &Path) -> String {
+    let absolute = |path: &Path| {
+        let path = if path.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            path
+        };
+        std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+    };
+    let from = absolute(single_file.parent().unwrap_or(Path::new("")));
+    let to = absolute(output_dir);
+    let shared = from
+        .components()
+        .zip(to.components())
+        .take_while(|(from, to)| from == to)
+        .count();
+    let mut root = "../".repeat(from.components().count() - shared);
+    for component in to.components().skip(shared) {
+        root.push_str(&component.as_os_str().to_string_lossy());
+        root.push('/');
+    }
+    root
+}
+
+// Save one image as images/<sha256>.<ext> and return that path relative to the
+// output directory, or None to keep linking the original
+async fn download_image(
+    base: &Url,
+    src: &str,
+    config: &CrawlerConfig,
+    scheduler: &Scheduler,
+) -> Option<String> {
+    if src.is_empty() || src.starts_with("data:") {
+        return None;
+    }
+    let url = base.join(src).ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+    let mut website = new_website(url.as_str(), config, scheduler);
+    website.configuration.depth = 0;
+    website.with_limit(1);
+    if scrape_with_timeout(&mut website, config, scheduler)
+        .await
+        .is_err()
+    {
+        warn!("Timed out downloading image {}", url);
+        return None;
+    }
+    let page = website.get_pages()?.first()?;
+    let bytes = page.get_html_bytes_u8();
+    if !page.status_code.is_success() || bytes.is_empty() {
+        warn!(
+            "Failed to download image {}: HTTP {}",
+            url, page.status_code
+        );
+        return None;
+    }
+    let content_type = page
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get("content-type"))
+        .and_then(|value| value.to_str().ok());
+    let file_name = format!(
+        "{}.{}",
+        sha256_hex(bytes),
+        image_extension(&url, content_type)
+    );
+    let dir = config.output_dir.join(IMAGES_DIR);
+    let path = dir.join(&file_name);
+    // Identical content hashes to the same name, so an existing file is already right
+    if !path.exists()
+        && let Err(e) = create_dir_all(&dir).and_then(|()| fs::write(&path, bytes))
+    {
+        warn!("Failed to save image {}: {}", path.display(), e);
+        return None;
+    }
+    debug!("Saved image {} as {}", url, path.display());
+    Some(format!("{}/{}", IMAGES_DIR, file_name))
+}
+
+// Lowercase hex SHA-256 of `bytes`
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// File extension for a downloaded image: the URL's own, else one from the Content-Type
+fn image_extension(url: &Url, content_type: Option<&str>) -> String {
+    let from_path = url
+        .path()
+        .rsplit_once('.')
+        .map(|(_, extension)| extension)
+        .filter(|extension| {
+            (1..=5).contains(&extension.len())
+                && extension.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+    if let Some(extension) = from_path {
+        return extension.to_ascii_lowercase();
+    }
+    let subtype = content_type
+        .and_then(|value| value.split(';').next())
+        .and_then(|mime| mime.trim().strip_prefix("image/"));
+    match subtype {
+        Some("jpeg") => "jpg".to_string(),
+        Some("svg+xml") => "svg".to_string(),
+        Some(subtype) if subtype.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            subtype.to_ascii_lowercase()
+        }
+        _ => "img".to_string(),
+    }
+}
+
+const ASSETS_DIR: &str = "assets";
+
+// Write a non-HTML response as is, named after its URL like a Markdown page
+fn save_asset(url: &str, body: &[u8], output_dir: &Path) -> Result<(), CrawlerError> {
+    let dir = output_dir.join(ASSETS_DIR);
+    create_dir_all(&dir)?;
+    let file_name = url_to_file_name(url);
+    let file_name = file_name.strip_suffix(".md").unwrap_or(&file_name);
+    let path = dir.join(file_name);
+    fs::write(&path, body)?;
+    debug!("Saved asset file: {}", path.display());
+    Ok(())
+}
+
+const JSONL_FILE: &str = "output.jsonl";
+
+// One line of output.jsonl
+#[derive(Serialize)]
+struct JsonlRecord<'a> {
+    url: &'a str,
+    title: Option<&'a str>,
+    markdown: &'a str,
+}
+
+// The `url` of each output.jsonl line, all that is needed to tell which pages it has
+#[derive(Deserialize)]
+struct JsonlUrl {
+    url: String,
+}
+
+// Final URLs output.jsonl has a record for; none if it does not exist yet
+fn load_jsonl_urls(output_dir: &Path) -> HashSet<String> {
+    let path = output_dir.join(JSONL_FILE);
+    let Ok(content) = fs::read_to_string(&path) else {
+        return HashSet::new();
+    };
+    content
+        .lines()
+        .filter_map(|line| match serde_json::from_str::<JsonlUrl>(line) {
+            Ok(record) => Some(record.url),
+            Err(e) => {
+                warn!("Ignoring malformed line in {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect()
+}
+
+// Open output.jsonl for appending, so several domains and runs share one file
+fn open_jsonl(output_dir: &Path) -> Result<BufWriter<File>, CrawlerError> {
+    create_dir_all(output_dir)?;
+    let path = output_dir.join(JSONL_FILE);
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    debug!("Appending pages to {}", path.display());
+    Ok(BufWriter::new(file))
+}
+
+// Serialize one page as a single line; JSON escapes the newlines inside the Markdown
+fn write_jsonl_record(writer: &mut impl Write, page: &PageResult) -> Result<(), CrawlerError> {
+    let record = JsonlRecord {
+        url: &page.final_url,
+        title: page.title.as_deref(),
+        markdown: &page.markdown,
+    };
+    serde_json::to_writer(&mut *writer, &record)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+// Relative path mirroring the URL's path, one directory per segment: /docs/guide/intro
+// becomes docs/guide/intro.md. A page with others below it (`is_parent`) is the
+// index.md of its own directory, as the site root is, so /docs becomes docs/index.md
+// beside docs/intro.md. A query string is folded into the file name, as
+// url_to_file_name does.
+fn url_to_nested_path(url: &str, is_parent: bool) -> PathBuf {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = without_scheme.split_once('/').map_or("", |(_, path)| path);
+    let path = path.split('#').next().unwrap_or_default();
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+
+    let mut segments: Vec<String> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(sanitize_path_segment)
+        .collect();
+    if path.is_empty() || path.ends_with('/') || is_parent {
+        segments.push("index".to_string());
+    }
+    if !query.is_empty() {
+        let last = segments.last_mut().expect("at least one segment");
+        last.push('_');
+        last.push_str(&sanitize_path_segment(query));
+    }
+    let mut nested: PathBuf = segments.iter().collect();
+    nested.set_extension(match nested.extension() {
+        Some(extension) => format!("{}.md", extension.to_string_lossy()),
+        None => "md".to_string(),
+    });
+    nested
+}
+
+// One URL path segment as a file or directory name: anything but letters, digits,
+// '-', '_' and '.' becomes '_', and "." or ".." cannot climb out of the output directory
+fn sanitize_path_segment(segment: &str) -> String {
+    let sanitized: String = segment
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if sanitized.chars().all(|c| c == '.') {
+        sanitized.replace('.', "_")
+    } else {
+        sanitized
+    }
+}
+
+// Flatten the URL path into a file name, e.g. /docs/intro/ -> docs_intro.md
+fn url_to_file_name(url: &str) -> String {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = without_scheme.split_once('/').map_or("", |(_, path)| path);
+    let path = path.split('#').next().unwrap_or_default();
+
+    let stem: String = path
+        .trim_matches('/')
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    if stem.is_empty() {
+        "index.md".to_string()
+    } else {
+        stem + ".md"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn markdown(html: &str) -> String {
+        html_to_markdown(html, MarkdownOptions::default())
+    }
+
+    // Serve `routes` (path -> body, where {origin} stands for the server's own origin)
+    // over HTTP on a free local port until the test process exits, answering 404 for
+    // every other path; returns the origin
+    fn serve(routes: &[(&str, &str)]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind a local port");
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        let routes: HashMap<String, String> = routes
+            .iter()
+            .map(|(path, body)| (path.to_string(), body.replace("{origin}", &origin)))
+            .collect();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut stream = stream;
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => request.extend_from_slice(&buf[..read]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request.split(' ').nth(1).unwrap_or("/");
+                let (status, body) = match routes.get(path) {
+                    Some(body) => ("200 OK", body.as_str()),
+                    None => ("404 Not Found", ""),
+                };
+                let content_type = if path.ends_with(".xml") || path.ends_with("/feed") {
+                    "application/xml"
+                } else {
+                    "text/html; charset=utf-8"
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    content_type,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        origin
+    }
+
+    // An empty directory of its own under the system temp directory
+    fn output_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("web-crawler-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    const PAGE: &str = "<html><head><title>Page</title></head><body>\
+        <p>Enough text on this page to count as real content when it is converted.</p>\
+        </body></html>";
+
+    fn page(url: &str, markdown: &str) -> PageResult {
+        PageResult {
+            url: url.to_string(),
+            final_url: url.to_string(),
+            title: None,
+            links: Vec::new(),
+            markdown: markdown.to_string(),
+            crawled_at: Utc::now(),
+            bytes: markdown.len(),
+            lastmod: None,
+            word_count: 0,
+            reading_minutes: 0,
+            suspicious_empty: false,
+        }
+    }
+
+    fn manifest(entries: &[(&str, &str)]) -> Manifest {
+        entries
+            .iter()
+            .map(|(url, markdown)| {
+                let entry = ManifestEntry {
+                    lastmod: None,
+                    content_hash: Some(sha256_hex(markdown.as_bytes())),
+                };
+                (url.to_string(), entry)
+            })
+            .collect()
+    }
+
+    async fn sitemap_pages(sitemap_url: &str, config: &CrawlerConfig) -> Vec<String> {
+        let scheduler = Scheduler::new(config);
+        get_all_page_urls_from_sitemaps(&[sitemap_url.to_string()], config, &scheduler)
+            .await
+            .expect("sitemaps are read")
+            .pages
+    }
+
+    #[test]
+    fn converts_headings_paragraphs_and_emphasis() {
+        assert_eq!(
+            markdown("<h1>Title</h1><p>Some <strong>bold</strong> and <em>soft</em> text.</p>"),
+            "# Title\n\nSome **bold** and *soft* text.\n"
+        );
+    }
+
+    #[test]
+    fn converts_links_and_images() {
+        assert_eq!(
+            markdown(r#"<p>See <a href="/docs">the docs</a>.</p><p><img src="a.png" alt="A"></p>"#),
+            "See [the docs](/docs).\n\n![A](a.png)\n"
+        );
+    }
+
+    #[test]
+    fn converts_lists() {
+        assert_eq!(
+            markdown("<ul><li>one</li><li>two</li></ul><ol><li>first</li><li>second</li></ol>"),
+            "- one\n- two\n\n1. first\n2. second\n"
+        );
+    }
+
+    #[test]
+    fn list_item_paragraphs_stay_inside_the_item() {
+        assert_eq!(
+            markdown("<ul><li><p>first</p><p>second</p></li><li>next</li></ul>"),
+            "- first\n\n    second\n- next\n"
+        );
+        assert_eq!(
+            markdown("<ul><li>a<ul><li><p>b</p><p>c</p></li></ul></li></ul>"),
+            "- a\n    - b\n\n        c\n"
+        );
+    }
+
+    #[test]
+    fn list_item_line_breaks_are_indented_under_the_item() {
+        assert_eq!(markdown("<ol><li>a<br>b</li></ol>"), "1. a\n    b\n");
+    }
+
+    #[test]
+    fn quotes_inside_list_items_are_indented_under_the_item() {
+        assert_eq!(
+            markdown("<ul><li>x<blockquote>q</blockquote></li></ul>"),
+            "- x\n\n    > q\n"
+        );
+        assert_eq!(
+            markdown(
+                "<ol><li>x<blockquote><p>q1</p><p>q2</p></blockquote>after</li><li>y</li></ol>"
+            ),
+            "1. x\n\n    > q1\n    >\n    > q2\n\n    after\n2. y\n"
+        );
+    }
+
+    #[test]
+    fn definition_paragraphs_stay_inside_the_definition() {
+        assert_eq!(
+            markdown("<dl><dt>t</dt><dd><p>d1</p><p>d2</p></dd></dl>"),
+            "**t**\n    d1\n\n    d2\n"
+        );
+        // The next term starts flush left after a blank line, not inside the description
+        assert_eq!(
+            markdown("<dl><dt>a</dt><dd>x</dd><dt>b</dt><dd>y</dd></dl>"),
+            "**a**\n    x\n\n**b**\n    y\n"
+        );
+    }
+
+    #[test]
+    fn converts_code_blocks_and_quotes() {
+        assert_eq!(
+            markdown("<pre><code>let x = 1;\n</code></pre><blockquote><p>Quoted</p></blockquote>"),
+            "```\nlet x = 1;\n```\n\n> Quoted\n"
+        );
+    }
+
+    #[test]
+    fn drops_scripts_and_styles() {
+        assert_eq!(
+            markdown("<style>p { color: red }</style><p>Text</p><script>alert(1)</script>"),
+            "Text\n"
+        );
+    }
+
+    #[test]
+    fn linked_picture_stays_inside_the_link() {
+        assert_eq!(
+            markdown(
+                r#"<p><a href="/big.png"><picture><source srcset="a.webp"><img src="a.png" alt="A"></picture> Full size</a></p>"#
+            ),
+            "[![A](a.png) Full size](/big.png)\n"
+        );
+    }
+
+    #[test]
+    fn clean_html_keeps_preformatted_text_exactly() {
+        let options = MarkdownOptions::default();
+        assert_eq!(
+            html_to_clean_html(
+                "<p>a</p>\n\n\n<pre>a\n\n\n\nb   </pre>\n\n\n<p>b</p>  ",
+                options
+            ),
+            "<p>a</p>\n\n<pre>a\n\n\n\nb   </pre>\n\n<p>b</p>\n"
+        );
+        assert_eq!(
+            html_to_clean_html("<pre>  x  \n\n\n  y  </pre>", options),
+            "<pre>  x  \n\n\n  y  </pre>\n"
+        );
+    }
+
+    #[test]
+    fn single_file_images_are_linked_from_the_document_itself() {
+        assert_eq!(
+            single_file_root(Path::new("out/all.md"), Path::new("out")),
+            ""
+        );
+        assert_eq!(
+            single_file_root(Path::new("all.md"), Path::new("out")),
+            "out/"
+        );
+        assert_eq!(
+            single_file_root(Path::new("docs/all.md"), Path::new("out")),
+            "../out/"
+        );
+        assert_eq!(
+            single_file_root(Path::new("/srv/site/all.md"), Path::new("/srv/out/pages")),
+            "../out/pages/"
+        );
+    }
+
+    #[test]
+    fn page_images_are_linked_from_the_page_file() {
+        let config = CrawlerConfig {
+            preserve_structure: true,
+            ..CrawlerConfig::default()
+        };
+        let saved_as = markdown_path(
+            "https://example.com/docs/guide/intro",
+            &config,
+            &HashSet::new(),
+        );
+        assert_eq!(relative_root(&saved_as), "../../");
+    }
+
+    #[test]
+    fn preserve_structure_puts_a_page_with_children_in_its_directory() {
+        let config = CrawlerConfig {
+            preserve_structure: true,
+            ..CrawlerConfig::default()
+        };
+        let urls = [
+            "https://example.com/",
+            "https://example.com/docs",
+            "https://example.com/docs/intro",
+            "https://example.com/docs/guide/setup",
+        ]
+        .map(String::from);
+        let parents = parent_pages(&urls);
+        let paths: Vec<PathBuf> = urls
+            .iter()
+            .map(|url| markdown_path(url, &config, &parents))
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "index.md",
+                "docs/index.md",
+                "docs/intro.md",
+                "docs/guide/setup.md"
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            markdown_path("https://example.com/docs?page=2", &config, &parents),
+            PathBuf::from("docs/index_page_2.md")
+        );
+    }
+
+    #[test]
+    fn preserve_structure_keeps_leaf_pages_as_files() {
+        assert_eq!(
+            url_to_nested_path("https://example.com/docs/intro", false),
+            PathBuf::from("docs/intro.md")
+        );
+        assert_eq!(
+            url_to_nested_path("https://example.com/v1.2/notes", false),
+            PathBuf::from("v1.2/notes.md")
+        );
+        assert_eq!(
+            url_to_nested_path("https://example.com/../etc", false),
+            PathBuf::from("__/etc.md")
+        );
+    }
+
+    #[test]
+    fn flat_file_names_join_the_path_segments() {
+        assert_eq!(
+            url_to_file_name("https://example.com/docs/intro/"),
+            "docs_intro.md"
+        );
+        assert_eq!(url_to_file_name("https://example.com/"), "index.md");
+    }
+
+    #[test]
+    fn heading_text_drops_inline_markdown() {
+        assert_eq!(
+            heading_text(" **Install** [the `cli`](/cli) ![logo](l.png) "),
+            "Install the cli logo"
+        );
+    }
+
+    #[test]
+    fn script_bundles_with_little_text_look_client_rendered() {
+        let app =
+            r#"<html><body><div id="app"></div><script src="/app.js"></script></body></html>"#;
+        assert!(looks_client_rendered(app, &markdown(app)));
+        let article = format!(
+            r#"<html><body><p>{}</p><script src="/analytics.js"></script></body></html>"#,
+            "Server-rendered text. ".repeat(20)
+        );
+        assert!(!looks_client_rendered(&article, &markdown(&article)));
+    }
+
+    #[test]
+    fn converts_every_heading_level() {
+        assert_eq!(
+            markdown("<h3>Title</h3><h4>Four</h4><h5>Five</h5><h6>Six</h6>"),
+            "### Title\n\n#### Four\n\n##### Five\n\n###### Six\n"
+        );
+    }
+
+    #[test]
+    fn image_without_alt_gets_empty_alt_text() {
+        assert_eq!(
+            markdown(r#"<p><img src="/a.png" alt="A"> <img src="/b.png"></p>"#),
+            "![A](/a.png) ![](/b.png)\n"
+        );
+    }
+
+    #[test]
+    fn decodes_named_and_numeric_entities_and_keeps_stray_ampersands() {
+        assert_eq!(
+            markdown("<p>Fish &amp; chips, caf&#233; and caf&#xE9;, AT&T &lt;b&gt;</p>"),
+            "Fish & chips, café and café, AT&T <b>\n"
+        );
+    }
+
+    #[test]
+    fn drops_noscript_and_script_holding_closing_tags() {
+        assert_eq!(
+            markdown(
+                "<p>a</p><noscript>Enable JavaScript</noscript>\
+                 <script type=\"text/javascript\">var s = '</p>';</script><p>b</p>"
+            ),
+            "a\n\nb\n"
+        );
+    }
+
+    #[test]
+    fn nested_ordered_lists_number_their_items_independently() {
+        assert_eq!(
+            markdown("<ol><li>a</li><li>b<ol><li>x</li><li>y</li></ol></li><li>c</li></ol>"),
+            "1. a\n2. b\n    1. x\n    2. y\n3. c\n"
+        );
+    }
+
+    #[test]
+    fn self_closing_tags_open_and_close_at_once() {
+        assert_eq!(
+            markdown(r#"<p>one<br/>two <img src="a.png" alt="A"/></p><hr/><p>three</p>"#),
+            "one\ntwo ![A](a.png)\n\n---\n\nthree\n"
+        );
+    }
+
+    #[test]
+    fn unterminated_tag_at_the_end_is_kept_as_text() {
+        assert_eq!(markdown("<p>end</p><div"), "end\n\n<div\n");
+        assert_eq!(markdown("<p>end</p><div class="), "end\n\n<div class=\n");
+    }
+
+    #[test]
+    fn fences_pre_and_backticks_inline_code() {
+        assert_eq!(
+            markdown("<pre>fn main(){}</pre><p>Call <code>main()</code> now</p>"),
+            "```\nfn main(){}\n```\n\nCall `main()` now\n"
+        );
+    }
+
+    #[test]
+    fn converts_a_table_with_a_header_row() {
+        assert_eq!(
+            markdown(
+                "<table><tr><th>Name</th><th>Value</th></tr><tr><td>a</td><td>1</td></tr></table>"
+            ),
+            "| Name | Value |\n| --- | --- |\n| a | 1 |\n"
+        );
+    }
+
+    #[test]
+    fn strip_boilerplate_drops_site_chrome() {
+        let html = "<header>Site name</header><nav>Menu</nav><main><p>Body text</p></main>\
+            <aside>Related</aside><footer>Footer text</footer>";
+        let options = MarkdownOptions {
+            strip_boilerplate: true,
+            ..MarkdownOptions::default()
+        };
+        assert_eq!(html_to_markdown(html, options), "Body text\n");
+        assert!(markdown(html).contains("Footer text"));
+    }
+
+    #[test]
+    fn nests_inline_formatting() {
+        assert_eq!(
+            markdown(
+                r#"<p><a href="x"><strong>Bold link</strong></a> and <em><strong><a href="y">all three</a></strong></em></p>"#
+            ),
+            "[**Bold link**](x) and ***[all three](y)***\n"
+        );
+    }
+
+    #[test]
+    fn quotes_every_paragraph_of_a_blockquote() {
+        assert_eq!(
+            markdown("<blockquote><p>One</p><p>Two</p></blockquote>"),
+            "> One\n>\n> Two\n"
+        );
+        assert_eq!(
+            markdown("<blockquote><p>Outer</p><blockquote><p>Inner</p></blockquote></blockquote>"),
+            "> Outer\n>\n> > Inner\n"
+        );
+    }
+
+    #[test]
+    fn runs_of_blank_lines_collapse_to_one() {
+        assert_eq!(markdown("<p>a</p>\n\n\n\n\n<p>b</p>"), "a\n\nb\n");
+        assert_eq!(markdown("<p>a</p><br><br><br><br><br><p>b</p>"), "a\n\nb\n");
+        assert_eq!(normalize_blank_lines("a\n\n\n\n\n\nb\n"), "a\n\nb\n");
+        assert_eq!(
+            normalize_blank_lines("```\nx\n\n\n\ny\n```\n"),
+            "```\nx\n\n\n\ny\n```\n"
+        );
+    }
+
+    #[test]
+    fn table_cell_spanning_columns_keeps_later_cells_aligned() {
+        assert_eq!(
+            markdown(
+                r#"<table><tr><th colspan="2">Both</th><th>C</th></tr><tr><td>a</td><td>b</td><td>c</td></tr></table>"#
+            ),
+            "| Both |  | C |\n| --- | --- | --- |\n| a | b | c |\n"
+        );
+    }
+
+    #[test]
+    fn table_escapes_pipes_in_cells() {
+        assert_eq!(
+            markdown("<table><tr><th>Op</th></tr><tr><td>a|b</td></tr></table>"),
+            "| Op |\n| --- |\n| a\\|b |\n"
+        );
+    }
+
+    #[test]
+    fn table_without_header_cells_uses_its_first_row() {
+        assert_eq!(
+            markdown(
+                "<table><tr><td>first</td><td>row</td></tr><tr><td>second</td><td>row</td></tr></table>"
+            ),
+            "| first | row |\n| --- | --- |\n| second | row |\n"
+        );
+    }
+
+    #[test]
+    fn blank_line_ends_a_list() {
+        assert_eq!(
+            markdown("<ol><li>a<ul><li>b</li></ul></li><li>c</li></ol><p>after</p>"),
+            "1. a\n    - b\n2. c\n\nafter\n"
+        );
+        assert_eq!(
+            markdown("<ul><li>item</li></ul><h2>Next</h2>"),
+            "- item\n\n## Next\n"
+        );
+    }
+
+    #[test]
+    fn plain_text_has_no_markdown_syntax() {
+        let options = MarkdownOptions {
+            plain_text: true,
+            ..MarkdownOptions::default()
+        };
+        let text = html_to_markdown(
+            "<h1>Title</h1><p>Some <strong>bold</strong>, <em>soft</em> and a \
+             <a href=\"/x\">link</a>.</p><ul><li>item</li></ul><pre>code</pre>",
+            options,
+        );
+        assert_eq!(
+            text,
+            "Title\n\nSome bold, soft and a link.\n\nitem\n\ncode\n"
+        );
+        assert!(!text.contains(['#', '*', '[', '`']));
+    }
+
+    #[test]
+    fn heading_slugs_follow_github_and_number_repeats() {
+        assert_eq!(heading_slug("Hello, World!"), "hello-world");
+        assert_eq!(heading_slug("Café au lait"), "café-au-lait");
+        assert_eq!(heading_slug("snake_case and-dash"), "snake_case-and-dash");
+        let mut used = HashSet::new();
+        let slugs: Vec<String> = ["intro", "intro", "intro"]
+            .iter()
+            .map(|slug| unique_slug(slug, &mut used))
+            .collect();
+        assert_eq!(slugs, ["intro", "intro-1", "intro-2"]);
+    }
+
+    #[test]
+    fn table_of_contents_links_every_heading_once() {
+        let options = MarkdownOptions {
+            toc: true,
+            ..MarkdownOptions::default()
+        };
+        assert_eq!(
+            html_to_markdown(
+                "<h1>Intro</h1><h2>Intro</h2><h2>Hello, <em>World</em>!</h2>\
+                 <pre># not a heading</pre>",
+                options
+            ),
+            "- [Intro](#intro)\n    - [Intro](#intro-1)\n    - [Hello, World!](#hello-world)\n\n\
+             # Intro\n\n## Intro\n\n## Hello, *World*!\n\n```\n# not a heading\n```\n"
+        );
+    }
+
+    #[test]
+    fn comments_and_cdata_are_never_shown() {
+        assert_eq!(
+            markdown("<p>a</p><!-- <p>hidden</p> --><p>b<![CDATA[ secret > ]]></p>"),
+            "a\n\nb\n"
+        );
+        assert_eq!(markdown("<p>a</p><!-- never closed <p>c</p>"), "a\n");
+    }
+
+    #[test]
+    fn div_separated_text_becomes_paragraphs() {
+        assert_eq!(
+            markdown("<div>First block</div><div>Second block</div><div><div>Third</div></div>"),
+            "First block\n\nSecond block\n\nThird\n"
+        );
+    }
+
+    #[test]
+    fn heading_offset_demotes_headings_down_to_h6() {
+        let offset = |heading_offset| MarkdownOptions {
+            heading_offset,
+            ..MarkdownOptions::default()
+        };
+        assert_eq!(
+            html_to_markdown("<h1>A</h1><h2>B</h2><h5>E</h5><h6>F</h6>", offset(1)),
+            "## A\n\n### B\n\n###### E\n\n###### F\n"
+        );
+        assert_eq!(
+            html_to_markdown("<h1>A</h1><h3>C</h3>", offset(u8::MAX)),
+            "###### A\n\n###### C\n"
+        );
+    }
+
+    #[test]
+    fn table_of_contents_nests_demoted_headings_from_the_top() {
+        let options = MarkdownOptions {
+            heading_offset: 3,
+            toc: true,
+            ..MarkdownOptions::default()
+        };
+        assert_eq!(
+            html_to_markdown("<h1>Intro</h1><h2>Part</h2><h6>Deep</h6>", options),
+            "- [Intro](#intro)\n    - [Part](#part)\n        - [Deep](#deep)\n\n\
+             #### Intro\n\n##### Part\n\n###### Deep\n"
+        );
+    }
+
+    #[test]
+    fn dead_links_become_their_text() {
+        assert_eq!(
+            markdown(
+                r##"<p><a href="javascript:void(0)">Menu</a> <a href=" JavaScript:go()">Go</a> <a href="">Empty</a> <a href="#">Top</a> <a href="#usage">Usage</a></p>"##
+            ),
+            "Menu Go Empty Top [Usage](#usage)\n"
+        );
+    }
+
+    #[test]
+    fn tel_and_mailto_links_are_kept_whole() {
+        assert_eq!(
+            markdown(
+                r#"<p><a href="tel:+1 555 0100">Call us</a> or <a href="mailto:help@example.com?subject=Hi">mail</a></p>"#
+            ),
+            "[Call us](tel:+15550100) or [mail](mailto:help@example.com?subject=Hi)\n"
+        );
+    }
+
+    #[test]
+    fn keep_dead_links_writes_them_as_they_are() {
+        let options = MarkdownOptions {
+            keep_dead_links: true,
+            ..MarkdownOptions::default()
+        };
+        assert_eq!(
+            html_to_markdown(
+                r##"<p><a href="javascript:void(0)">Menu</a> <a href="">Empty</a> <a href="#">Top</a></p>"##,
+                options
+            ),
+            "[Menu](javascript:void(0)) [Empty]() [Top](#)\n"
+        );
+    }
+
+    #[test]
+    fn normalize_url_collapses_trailing_slash_and_fragment() {
+        assert_eq!(
+            normalize_url("HTTPS://Example.COM/docs/#intro", &[]),
+            "https://example.com/docs"
+        );
+        assert_eq!(
+            normalize_url("https://example.com", &[]),
+            "https://example.com/"
+        );
+        assert_eq!(
+            normalize_url("https://example.com/", &[]),
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn normalize_url_removes_tracking_params_only() {
+        let tracking = CrawlerConfig::default().tracking_params;
+        assert_eq!(
+            normalize_url(
+                "https://example.com/a?utm_source=x&page=2&gclid=y",
+                &tracking
+            ),
+            "https://example.com/a?page=2"
+        );
+        assert_eq!(
+            normalize_url("https://example.com/a?utm_medium=email", &tracking),
+            "https://example.com/a"
+        );
+    }
+
+    #[test]
+    fn robots_disallow_blocks_matching_paths() {
+        let robots = parse_robots_txt("User-agent: *\nDisallow: /private\n", "web-crawler/0.1");
+        assert!(!robots.is_allowed("https://example.com/private/page"));
+        assert!(robots.is_allowed("https://example.com/public"));
+    }
+
+    #[test]
+    fn robots_longer_allow_reopens_a_disallowed_path() {
+        let robots = parse_robots_txt(
+            "User-agent: *\nDisallow: /docs\nAllow: /docs/public\n",
+            "web-crawler/0.1",
+        );
+        assert!(!robots.is_allowed("https://example.com/docs/secret"));
+        assert!(robots.is_allowed("https://example.com/docs/public/page"));
+    }
+
+    #[test]
+    fn robots_applies_the_group_naming_our_product_token() {
+        let content = "User-agent: *\nDisallow: /\n\n\
+            User-agent: Web-Crawler\nDisallow: /private\n";
+        let robots = parse_robots_txt(content, "web-crawler/0.1.0 (+https://example.com)");
+        assert!(robots.is_allowed("https://example.com/docs"));
+        assert!(!robots.is_allowed("https://example.com/private"));
+    }
+
+    #[test]
+    fn robots_falls_back_to_the_star_group() {
+        let content = "User-agent: otherbot\nDisallow: /\n\n\
+            User-agent: *\nDisallow: /private\nCrawl-delay: 2\n";
+        let robots = parse_robots_txt(content, "web-crawler/0.1.0");
+        assert!(robots.is_allowed("https://example.com/docs"));
+        assert!(!robots.is_allowed("https://example.com/private"));
+        assert_eq!(robots.crawl_delay, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn robots_agent_lines_in_a_row_share_one_group() {
+        let content = "User-agent: otherbot\nUser-agent: web-crawler\nDisallow: /a\n\n\
+            User-agent: *\nDisallow: /b\n";
+        let robots = parse_robots_txt(content, "web-crawler/0.1.0");
+        assert!(!robots.is_allowed("https://example.com/a"));
+        assert!(robots.is_allowed("https://example.com/b"));
+    }
+
+    #[test]
+    fn robots_longest_match_wins_and_allow_wins_a_tie() {
+        let content = "User-agent: *\nAllow: /docs\nDisallow: /docs/internal\n\
+            Allow: /same\nDisallow: /same\n";
+        let robots = parse_robots_txt(content, "web-crawler/0.1.0");
+        assert!(robots.is_allowed("https://example.com/docs/guide"));
+        assert!(!robots.is_allowed("https://example.com/docs/internal/page"));
+        assert!(robots.is_allowed("https://example.com/same"));
+    }
+
+    #[test]
+    fn robots_rules_are_for_the_first_rotated_user_agent() {
+        let config = CrawlerConfig {
+            user_agents: vec!["firstbot/1.0".to_string(), "secondbot/1.0".to_string()],
+            ..CrawlerConfig::default()
+        };
+        assert_eq!(config.robots_user_agent(), "firstbot/1.0");
+        assert_eq!(
+            CrawlerConfig::default().robots_user_agent(),
+            DEFAULT_USER_AGENT
+        );
+    }
+
+    #[test]
+    fn robots_patterns_support_wildcards_and_anchors() {
+        assert!(robots_pattern_matches("/*.pdf$", "/files/report.pdf"));
+        assert!(!robots_pattern_matches(
+            "/*.pdf$",
+            "/files/report.pdf?download"
+        ));
+        assert!(robots_pattern_matches(
+            "/search*q=",
+            "/search?lang=en&q=rust"
+        ));
+    }
+
+    #[test]
+    fn robots_lists_sitemaps_for_everyone() {
+        let robots = parse_robots_txt(
+            "Sitemap: https://example.com/sitemap.xml\nUser-agent: other\nDisallow: /\n",
+            "web-crawler/0.1",
+        );
+        assert_eq!(robots.sitemaps, ["https://example.com/sitemap.xml"]);
+        assert!(robots.is_allowed("https://example.com/"));
+    }
+
+    #[tokio::test]
+    async fn sitemap_urlset_lists_its_pages() {
+        let origin = serve(&[(
+            "/sitemap.xml",
+            r#"<?xml version="1.0"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://example.com/b</loc></url>
+  <url><loc>https://example.com/a/</loc><lastmod>2024-01-02</lastmod></url>
+</urlset>"#,
+        )]);
+        let pages = sitemap_pages(
+            &format!("{}/sitemap.xml", origin),
+            &CrawlerConfig::default(),
+        )
+        .await;
+        assert_eq!(pages, ["https://example.com/a", "https://example.com/b"]);
+    }
+
+    #[tokio::test]
+    async fn sitemap_index_reads_its_child_sitemaps() {
+        let child = r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://example.com/child</loc></url>
+</urlset>"#;
+        let index = r#"<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <sitemap><loc>/child.xml</loc></sitemap>
+</sitemapindex>"#;
+        let origin = serve(&[("/index.xml", index), ("/child.xml", child)]);
+        let pages =
+            sitemap_pages(&format!("{}/index.xml", origin), &CrawlerConfig::default()).await;
+        assert_eq!(pages, ["https://example.com/child"]);
+    }
+
+    #[tokio::test]
+    async fn sitemap_kind_comes_from_the_root_element_not_the_name() {
+        let child = r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://example.com/page.xml</loc></url>
+</urlset>"#;
+        let index = r#"<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <sitemap><loc>{origin}/sitemaps/pages</loc></sitemap>
+</sitemapindex>"#;
+        let origin = serve(&[("/index", index), ("/sitemaps/pages", child)]);
+        let pages = sitemap_pages(&format!("{}/index", origin), &CrawlerConfig::default()).await;
+        assert_eq!(pages, ["https://example.com/page.xml"]);
+    }
+
+    #[tokio::test]
+    async fn sitemap_indexes_listing_each_other_are_read_once() {
+        let first = r#"<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <sitemap><loc>/second.xml</loc></sitemap>
+  <sitemap><loc>/first.xml</loc></sitemap>
+  <sitemap><loc>/pages.xml</loc></sitemap>
+</sitemapindex>"#;
+        let second = r#"<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <sitemap><loc>/first.xml</loc></sitemap>
+</sitemapindex>"#;
+        let pages = r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://example.com/page</loc></url>
+</urlset>"#;
+        let origin = serve(&[
+            ("/first.xml", first),
+            ("/second.xml", second),
+            ("/pages.xml", pages),
+        ]);
+        let pages =
+            sitemap_pages(&format!("{}/first.xml", origin), &CrawlerConfig::default()).await;
+        assert_eq!(pages, ["https://example.com/page"]);
+    }
+
+    #[tokio::test]
+    async fn sitemap_after_a_byte_order_mark_and_whitespace_is_read() {
+        let sitemap = "\u{feff}\n  <?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+            <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\
+            <url><loc>https://example.com/page</loc></url></urlset>";
+        let origin = serve(&[("/sitemap.xml", sitemap)]);
+        let pages = sitemap_pages(
+            &format!("{}/sitemap.xml", origin),
+            &CrawlerConfig::default(),
+        )
+        .await;
+        assert_eq!(pages, ["https://example.com/page"]);
+    }
+
+    #[tokio::test]
+    async fn text_sitemap_lists_one_url_per_line() {
+        let sitemap = "https://example.com/a\n\n# comment\nhttps://example.com/b\r\n\
+            not a url\nhttps://example.com/c\n";
+        let origin = serve(&[("/sitemap.txt", sitemap)]);
+        let pages = sitemap_pages(
+            &format!("{}/sitemap.txt", origin),
+            &CrawlerConfig::default(),
+        )
+        .await;
+        assert_eq!(
+            pages,
+            [
+                "https://example.com/a",
+                "https://example.com/b",
+                "https://example.com/c"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn broken_sitemap_leaves_the_others_to_crawl() {
+        let valid = "<urlset><url><loc>https://example.com/valid</loc></url></urlset>";
+        let broken = "<urlset><url><loc>https://example.com/broken</loc></urlset>";
+        let origin = serve(&[("/valid.xml", valid), ("/broken.xml", broken)]);
+        let config = CrawlerConfig::default();
+        let scheduler = Scheduler::new(&config);
+        let sitemap = get_all_page_urls_from_sitemaps(
+            &[
+                format!("{}/broken.xml", origin),
+                format!("{}/valid.xml", origin),
+            ],
+            &config,
+            &scheduler,
+        )
+        .await
+        .expect("a broken sitemap is skipped");
+        assert!(
+            sitemap
+                .pages
+                .contains(&"https://example.com/valid".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn sitemap_index_listing_itself_is_read_once() {
+        let index = "<sitemapindex><sitemap><loc>/index.xml</loc></sitemap>\
+            <sitemap><loc>{origin}/index.xml#again</loc></sitemap>\
+            <sitemap><loc>/pages.xml</loc></sitemap></sitemapindex>";
+        let pages = "<urlset><url><loc>https://example.com/page</loc></url></urlset>";
+        let origin = serve(&[("/index.xml", index), ("/pages.xml", pages)]);
+        let pages =
+            sitemap_pages(&format!("{}/index.xml", origin), &CrawlerConfig::default()).await;
+        assert_eq!(pages, ["https://example.com/page"]);
+    }
+
+    #[tokio::test]
+    async fn sitemaps_nested_past_the_depth_limit_are_not_read() {
+        let index = |children: &[&str]| {
+            let entries: String = children
+                .iter()
+                .map(|child| format!("<sitemap><loc>{}</loc></sitemap>", child))
+                .collect();
+            format!("<sitemapindex>{}</sitemapindex>", entries)
+        };
+        let urlset = |page: &str| {
+            format!(
+                "<urlset><url><loc>https://example.com/{}</loc></url></urlset>",
+                page
+            )
+        };
+        let (top, middle) = (
+            index(&["/middle.xml", "/shallow.xml"]),
+            index(&["/deep.xml"]),
+        );
+        let (shallow, deep) = (urlset("shallow"), urlset("deep"));
+        let origin = serve(&[
+            ("/top.xml", &top),
+            ("/middle.xml", &middle),
+            ("/shallow.xml", &shallow),
+            ("/deep.xml", &deep),
+        ]);
+        let config = CrawlerConfig {
+            sitemap_max_depth: 1,
+            ..CrawlerConfig::default()
+        };
+        let pages = sitemap_pages(&format!("{}/top.xml", origin), &config).await;
+        assert_eq!(pages, ["https://example.com/shallow"]);
+    }
+
+    #[tokio::test]
+    async fn prefixed_sitemap_elements_are_read_and_image_locs_are_not_pages() {
+        let sitemap = r#"<sm:urlset xmlns:sm="http://www.sitemaps.org/schemas/sitemap/0.9"
+    xmlns:image="http://www.google.com/schemas/sitemap-image/1.1">
+  <sm:url>
+    <sm:loc>https://example.com/page</sm:loc>
+    <image:image><image:loc>https://example.com/photo.jpg</image:loc></image:image>
+  </sm:url>
+</sm:urlset>"#;
+        let origin = serve(&[("/sitemap.xml", sitemap)]);
+        let pages = sitemap_pages(
+            &format!("{}/sitemap.xml", origin),
+            &CrawlerConfig::default(),
+        )
+        .await;
+        assert_eq!(pages, ["https://example.com/page"]);
+    }
+
+    #[tokio::test]
+    async fn sitemap_entries_before_an_xml_error_are_kept() {
+        let child = r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://example.com/child</loc></url>
+</urlset>"#;
+        let index = r#"<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <sitemap><loc>/child.xml</loc></sitemap>
+  </sitemap>
+</sitemapindex>"#;
+        let pages = r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc>https://example.com/first</loc></url>
+  <url><loc>https://example.com/last</loc></oops>
+</urlset>"#;
+        let origin = serve(&[
+            ("/index.xml", index),
+            ("/child.xml", child),
+            ("/pages.xml", pages),
+        ]);
+        let config = CrawlerConfig::default();
+        assert_eq!(
+            sitemap_pages(&format!("{}/index.xml", origin), &config).await,
+            ["https://example.com/child"]
+        );
+        assert_eq!(
+            sitemap_pages(&format!("{}/pages.xml", origin), &config).await,
+            ["https://example.com/first", "https://example.com/last"]
+        );
+    }
+
+    #[test]
+    fn rss_feed_lists_item_links_on_its_host() {
+        let xml = "<rss><channel><link>https://example.com/</link>\
+            <item><link>https://example.com/post/1</link></item>\
+            <item><link>/post/2</link></item>\
+            <item><link>https://elsewhere.com/post</link></item></channel></rss>";
+        let urls = parse_feed("https://example.com/feed", xml, &CrawlerConfig::default()).unwrap();
+        assert_eq!(
+            urls,
+            ["https://example.com/post/1", "https://example.com/post/2"]
+        );
+    }
+
+    #[test]
+    fn atom_feed_lists_alternate_entry_links() {
+        let xml = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+  <entry><link href="https://example.com/a"/><link rel="enclosure" href="https://example.com/a.mp3"/></entry>
+</feed>"#;
+        let urls = parse_feed(
+            "https://example.com/atom.xml",
+            xml,
+            &CrawlerConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(urls, ["https://example.com/a"]);
+    }
+
+    #[tokio::test]
+    async fn save_html_skips_pages_dropped_as_duplicates() {
+        let origin = serve(&[("/a", PAGE), ("/b", PAGE)]);
+        let config = CrawlerConfig {
+            output_dir: output_dir("save-html-dedup"),
+            urls: vec![format!("{}/a", origin), format!("{}/b", origin)],
+            dedup_content: true,
+            save_html: true,
+            ..CrawlerConfig::default()
+        };
+        let result = Crawler::new(config.clone()).run(&origin).await.unwrap();
+        assert_eq!(result.pages.len(), 1);
+        let html_files: Vec<_> = fs::read_dir(&config.output_dir)
+            .unwrap()
+            .flatten()
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "html"))
+            .collect();
+        assert_eq!(html_files.len(), 1);
+        assert!(config.output_dir.join("a.html").exists());
+    }
+
+    #[test]
+    fn changes_list_added_and_modified_pages() {
+        let previous = manifest(&[
+            ("https://example.com/a", "old"),
+            ("https://example.com/b", "same"),
+        ]);
+        let current = manifest(&[
+            ("https://example.com/a", "new"),
+            ("https://example.com/b", "same"),
+            ("https://example.com/c", "added"),
+        ]);
+        let result = CrawlResult {
+            domain: "https://example.com".to_string(),
+            collected: ["a", "b", "c"]
+                .map(|path| format!("https://example.com/{}", path))
+                .to_vec(),
+            pages: vec![
+                page("https://example.com/a", "new"),
+                page("https://example.com/b", "same"),
+                page("https://example.com/c", "added"),
+            ],
+            ..CrawlResult::default()
+        };
+        let changes = find_changes(&result, &previous, &current);
+        assert_eq!(
+            changes.added,
+            BTreeSet::from(["https://example.com/c".to_string()])
+        );
+        assert_eq!(
+            changes.modified,
+            BTreeSet::from(["https://example.com/a".to_string()])
+        );
+        assert!(changes.removed.is_empty());
+    }
+
+    #[test]
+    fn only_unlisted_and_gone_pages_count_as_removed() {
+        let previous = manifest(&[
+            ("https://example.com/skipped", "x"),
+            ("https://example.com/unlisted", "x"),
+            ("https://example.com/gone", "x"),
+            ("https://example.com/erroring", "x"),
+            ("https://example.com.other.net/page", "x"),
+            ("https://other.com/page", "x"),
+        ]);
+        let failure = |path: &str, status| PageFailure {
+            url: format!("https://example.com/{}", path),
+            error: String::new(),
+            status,
+        };
+        let result = CrawlResult {
+            domain: "https://example.com".to_string(),
+            // Listed, but skipped by --since or --limit, so neither saved nor failed
+            collected: ["skipped", "gone", "erroring"]
+                .map(|path| format!("https://example.com/{}", path))
+                .to_vec(),
+            failures: vec![failure("gone", Some(410)), failure("erroring", Some(503))],
+            ..CrawlResult::default()
+        };
+        let changes = find_changes(&result, &previous, &previous);
+        assert_eq!(
+            changes.removed,
+            BTreeSet::from([
+                "https://example.com/gone".to_string(),
+                "https://example.com/unlisted".to_string(),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn recrawling_an_unchanged_site_lists_no_changes() {
+        let origin = serve(&[
+            (
+                "/sitemap.xml",
+                "<urlset><url><loc>{origin}/a</loc></url><url><loc>{origin}/b</loc></url></urlset>",
+            ),
+            ("/a", PAGE),
+            ("/b", PAGE),
+        ]);
+        let config = CrawlerConfig {
+            output_dir: output_dir("diff-previous"),
+            sitemaps: vec![format!("{}/sitemap.xml", origin)],
+            diff_previous: true,
+            ..CrawlerConfig::default()
+        };
+        let changes = || {
+            let json = fs::read_to_string(config.output_dir.join(CHANGES_FILE)).unwrap();
+            serde_json::from_str::<serde_json::Value>(&json).unwrap()[&origin].clone()
+        };
+        Crawler::new(config.clone()).run(&origin).await.unwrap();
+        assert_eq!(changes()["added"].as_array().unwrap().len(), 2);
+        Crawler::new(config.clone()).run(&origin).await.unwrap();
+        let second = changes();
+        for kind in ["added", "modified", "removed"] {
+            assert_eq!(
+                second[kind],
+                serde_json::json!([]),
+                "{} after a recrawl",
+                kind
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn waiting_to_retry_frees_the_fetch_slot() {
+        let scheduler = Scheduler::new(&CrawlerConfig {
+            concurrency: 1,
+            ..CrawlerConfig::default()
+        });
+        let mut slot = Some(scheduler.acquire("https://example.com/a").await);
+        let retry = scheduler.wait_for_retry(
+            &mut slot,
+            "https://example.com/a",
+            Duration::from_millis(300),
+        );
+        let other = async {
+            tokio::time::timeout(
+                Duration::from_millis(200),
+                scheduler.acquire("https://other.com/b"),
+            )
+            .await
+            .is_ok()
+        };
+        let ((), other_fetched) = tokio::join!(retry, other);
+        assert!(
+            other_fetched,
+            "another page waited for the retrying page's slot"
+        );
+        assert!(slot.is_some());
+    }
+
+    #[test]
+    fn each_crawler_rotates_user_agents_from_the_first() {
+        let config = CrawlerConfig {
+            user_agents: vec!["a/1".to_string(), "b/1".to_string()],
+            ..CrawlerConfig::default()
+        };
+        let first = Scheduler::new(&config);
+        assert_eq!(first.next_user_agent(&config), "a/1");
+        assert_eq!(first.next_user_agent(&config), "b/1");
+        // A second crawler in the same process starts its own rotation
+        let second = Scheduler::new(&config);
+        assert_eq!(second.next_user_agent(&config), "a/1");
+        assert_eq!(first.next_user_agent(&config), "a/1");
+    }
+
+    #[test]
+    fn pages_are_flushed_during_the_crawl_by_default() {
+        assert_eq!(
+            CrawlerConfig::default().pages_per_flush(),
+            DEFAULT_FLUSH_EVERY
+        );
+        assert!(!CrawlerConfig::default().drops_flushed_markdown());
+        let jsonl = CrawlerConfig {
+            format: OutputFormat::Jsonl,
+            ..CrawlerConfig::default()
+        };
+        assert_eq!(jsonl.pages_per_flush(), DEFAULT_FLUSH_EVERY);
+        assert!(jsonl.drops_flushed_markdown());
+        let single_file = CrawlerConfig {
+            single_file: Some(PathBuf::from("all.md")),
+            flush_every: 5,
+            ..CrawlerConfig::default()
+        };
+        assert_eq!(single_file.pages_per_flush(), 5);
+    }
+
+    #[tokio::test]
+    async fn jsonl_pages_written_during_the_crawl_are_not_written_again() {
+        let paths: Vec<String> = (0..=DEFAULT_FLUSH_EVERY)
+            .map(|i| format!("/p{}", i))
+            .collect();
+        let routes: Vec<(&str, &str)> = paths.iter().map(|path| (path.as_str(), PAGE)).collect();
+        let origin = serve(&routes);
+        let config = CrawlerConfig {
+            output_dir: output_dir("jsonl-stream"),
+            format: OutputFormat::Jsonl,
+            urls: paths
+                .iter()
+                .map(|path| format!("{}{}", origin, path))
+                .collect(),
+            ..CrawlerConfig::default()
+        };
+        let result = Crawler::new(config.clone()).run(&origin).await.unwrap();
+        assert_eq!(result.flushed, paths.len());
+        let jsonl = fs::read_to_string(config.output_dir.join(JSONL_FILE)).unwrap();
+        assert_eq!(jsonl.lines().count(), paths.len());
+    }
+
+    #[tokio::test]
+    async fn dry_run_returns_the_urls_it_would_fetch() {
+        let origin = serve(&[("/a", PAGE), ("/b", PAGE)]);
+        let config = CrawlerConfig {
+            output_dir: output_dir("dry-run"),
+            dry_run: true,
+            urls: vec![format!("{}/a", origin), format!("{}/b", origin)],
+            ..CrawlerConfig::default()
+        };
+        let result = Crawler::new(config.clone()).run(&origin).await.unwrap();
+        assert_eq!(
+            result.planned,
+            [format!("{}/a", origin), format!("{}/b", origin)]
+        );
+        assert!(result.pages.is_empty());
+        assert!(!config.output_dir.join("a.md").exists());
+    }
+
+    #[tokio::test]
+    async fn unchanged_jsonl_pages_are_written_again_once_output_jsonl_is_gone() {
+        let origin = serve(&[("/a", PAGE), ("/b", PAGE)]);
+        let config = CrawlerConfig {
+            output_dir: output_dir("jsonl-missing"),
+            format: OutputFormat::Jsonl,
+            urls: vec![format!("{}/a", origin), format!("{}/b", origin)],
+            ..CrawlerConfig::default()
+        };
+        let jsonl = config.output_dir.join(JSONL_FILE);
+        Crawler::new(config.clone()).run(&origin).await.unwrap();
+        // Unchanged pages still in output.jsonl are not appended twice
+        Crawler::new(config.clone()).run(&origin).await.unwrap();
+        assert_eq!(fs::read_to_string(&jsonl).unwrap().lines().count(), 2);
+        fs::remove_file(&jsonl).unwrap();
+        Crawler::new(config.clone()).run(&origin).await.unwrap();
+        assert_eq!(fs::read_to_string(&jsonl).unwrap().lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn pages_sharing_a_last_segment_get_their_own_files() {
+        let origin = serve(&[("/docs/intro", PAGE), ("/guide/intro", PAGE)]);
+        let config = CrawlerConfig {
+            output_dir: output_dir("same-leaf"),
+            urls: vec![
+                format!("{}/docs/intro", origin),
+                format!("{}/guide/intro", origin),
+            ],
+            ..CrawlerConfig::default()
+        };
+        let result = Crawler::new(config.clone()).run(&origin).await.unwrap();
+        assert_eq!(result.pages.len(), 2);
+        assert!(config.output_dir.join("docs_intro.md").exists());
+        assert!(config.output_dir.join("guide_intro.md").exists());
+    }
+
+    #[test]
+    fn frontmatter_comes_before_the_title_and_body() {
+        let page = PageResult {
+            title: Some(r#"Say "hi": a \ guide"#.to_string()),
+            crawled_at: "2024-01-02T03:04:05Z".parse().unwrap(),
+            word_count: 3,
+            reading_minutes: 1,
+            ..page("https://example.com/hi", "Body text here.\n")
+        };
+        assert_eq!(
+            render_page(&page),
+            format!(
+                "---\n\
+                 title: \"Say \\\"hi\\\": a \\\\ guide\"\n\
+                 source_url: \"https://example.com/hi\"\n\
+                 date_crawled: 2024-01-02T03:04:05Z\n\
+                 word_count: 3\n\
+                 reading_minutes: 1\n\
+                 generator: \"web-crawler {}\"\n\
+                 ---\n\n\
+                 # Say \"hi\": a \\ guide\n\n\
+                 Body text here.\n",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+    }
+
+    #[test]
+    fn text_and_clean_html_name_the_crawler_version() {
+        let page = PageResult {
+            title: Some("Hi".to_string()),
+            ..page("https://example.com/hi", "Body text here.\n")
+        };
+        assert_eq!(
+            render_text(&page),
+            format!(
+                "Hi\n\nBody text here.\n\nGenerated by web-crawler {}\n",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert!(render_clean_html(&page).contains(&format!(
+            "<meta name=\"generator\" content=\"web-crawler {}\">\n",
+            env!("CARGO_PKG_VERSION")
+        )));
+    }
+
+    #[test]
+    fn url_filter_exclude_wins_over_an_overlapping_include() {
+        let patterns = |patterns: &[&str]| -> Vec<String> {
+            patterns.iter().map(|pattern| pattern.to_string()).collect()
+        };
+        let filter = UrlFilter::new(
+            &patterns(&["/docs/*", "/guide/*"]),
+            &patterns(&["/docs/internal/*", "/blog/*"]),
+        )
+        .unwrap();
+        assert!(filter.is_selected("https://example.com/docs/intro"));
+        assert!(filter.is_selected("https://example.com/guide/start?lang=en"));
+        assert!(!filter.is_selected("https://example.com/docs/internal/keys"));
+        assert!(!filter.is_selected("https://example.com/blog/post"));
+        assert!(!filter.is_selected("https://example.com/about"));
+
+        let exclude_only = UrlFilter::new(&[], &patterns(&["/blog/*"])).unwrap();
+        assert!(exclude_only.is_selected("https://example.com/about"));
+        assert!(!exclude_only.is_selected("https://example.com/blog/post"));
+    }
+
+    #[test]
+    fn decodes_latin1_from_the_header_or_a_meta_tag() {
+        assert_eq!(
+            decode_html(b"<p>caf\xe9</p>", Some("text/html; charset=ISO-8859-1")),
+            "<p>café</p>"
+        );
+        assert_eq!(
+            decode_html(b"<meta charset=\"iso-8859-1\"><p>caf\xe9</p>", None),
+            "<meta charset=\"iso-8859-1\"><p>café</p>"
+        );
+        assert_eq!(decode_html("<p>café</p>".as_bytes(), None), "<p>café</p>");
+    }
+
+    #[tokio::test]
+    async fn native_crawl_drops_off_site_links() {
+        let home = r#"<html><body><a href="/a">A</a> <a href="{origin}/b">B</a>
+<a href="https://elsewhere.example/x">Elsewhere</a></body></html>"#;
+        let origin = serve(&[("/", home), ("/a", PAGE), ("/b", PAGE)]);
+        let config = CrawlerConfig {
+            depth: 1,
+            ..CrawlerConfig::default()
+        };
+        let scheduler = Scheduler::new(&config);
+        let urls = native_crawl(&origin, &config, &scheduler, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(
+            urls,
+            [
+                format!("{}/", origin),
+                format!("{}/a", origin),
+                format!("{}/b", origin)
+            ]
+        );
+    }
+
+    #[test]
+    fn on_site_takes_subdomains_only_when_allowed() {
+        assert!(on_site("www.example.com", "example.com", false));
+        assert!(on_site("Example.com", "www.example.com", false));
+        assert!(!on_site("docs.example.com", "example.com", false));
+        assert!(on_site("docs.example.com", "example.com", true));
+        assert!(on_site("shop.example.co.uk", "www.example.co.uk", true));
+        assert!(!on_site("other.co.uk", "example.co.uk", true));
+        assert!(!on_site("elsewhere.com", "example.com", true));
+    }
+
+    #[test]
+    fn same_registrable_domain_compares_hosts_under_their_suffix() {
+        assert!(same_registrable_domain(
+            "https://cdn.example.com/sitemap.xml",
+            "https://www.example.com/"
+        ));
+        assert!(!same_registrable_domain(
+            "https://example.co.uk/",
+            "https://other.co.uk/"
+        ));
+        assert!(!same_registrable_domain(
+            "not a url",
+            "https://example.com/"
+        ));
+    }
+
+    #[test]
+    fn word_count_skips_markup_and_link_targets() {
+        assert_eq!(
+            count_words(
+                "# Title\n\nThe quick brown fox jumps over the lazy dog.\n\n\
+                 [link text](https://example.com/a-b) - *em*\n\n```\n```\n"
+            ),
+            13
+        );
+    }
+
+    #[tokio::test]
+    async fn since_keeps_pages_modified_on_or_after_the_date() {
+        let sitemap = "<urlset>\
+            <url><loc>{origin}/before</loc><lastmod>2023-12-31T23:59:59Z</lastmod></url>\
+            <url><loc>{origin}/on</loc><lastmod>2024-01-01T00:00:00+05:00</lastmod></url>\
+            <url><loc>{origin}/after</loc><lastmod>2024-01-02</lastmod></url>\
+            <url><loc>{origin}/undated</loc></url></urlset>";
+        let origin = serve(&[
+            ("/sitemap.xml", sitemap),
+            ("/before", PAGE),
+            ("/on", PAGE),
+            ("/after", PAGE),
+            ("/undated", PAGE),
+        ]);
+        let crawled = |skip_undated| {
+            let config = CrawlerConfig {
+                output_dir: output_dir(&format!("since-{}", skip_undated)),
+                sitemaps: vec![format!("{}/sitemap.xml", origin)],
+                since: NaiveDate::from_ymd_opt(2024, 1, 1),
+                skip_undated,
+                ..CrawlerConfig::default()
+            };
+            let origin = origin.clone();
+            async move {
+                let result = Crawler::new(config).run(&origin).await.unwrap();
+                let mut paths: Vec<String> = result
+                    .pages
+                    .iter()
+                    .map(|page| url_path(&page.url).to_string())
+                    .collect();
+                paths.sort();
+                paths
+            }
+        };
+        assert_eq!(crawled(false).await, ["/after", "/on", "/undated"]);
+        assert_eq!(crawled(true).await, ["/after", "/on"]);
+    }
+
+    #[test]
+    fn forgetting_a_domain_keeps_hosts_that_share_its_prefix() {
+        let mut visited: BTreeSet<String> = [
+            "https://example.com/a",
+            "https://www.example.com/b",
+            "https://example.com.evil/c",
+            "https://example.community/d",
+            "https://docs.example.com/e",
+        ]
+        .map(String::from)
+        .into();
+        forget_visited(&mut visited, "https://example.com");
+        assert_eq!(
+            visited,
+            [
+                "https://docs.example.com/e",
+                "https://example.com.evil/c",
+                "https://example.community/d"
+            ]
+            .map(String::from)
+            .into()
+        );
+    }
+
+    #[test]
+    fn flushed_pages_are_marked_visited_and_reported_as_they_are_appended() {
+        let config = CrawlerConfig {
+            output_dir: output_dir("flush-visited"),
+            format: OutputFormat::Jsonl,
+            ..CrawlerConfig::default()
+        };
+        let mut result = CrawlResult {
+            domain: "https://example.com".to_string(),
+            pages: vec![
+                page("https://example.com/a", "A\n"),
+                page("https://example.com/b", "B\n"),
+            ],
+            ..CrawlResult::default()
+        };
+        let mut report = CrawlReport {
+            domain: result.domain.clone(),
+            ..CrawlReport::default()
+        };
+        let mut state = SaveState::default();
+        flush_pages(&mut result, &config, &mut state, &mut report).unwrap();
+
+        assert_eq!(result.flushed, 2);
+        assert!(result.pages.iter().all(|page| page.markdown.is_empty()));
+        let jsonl = fs::read_to_string(config.output_dir.join(JSONL_FILE)).unwrap();
+        assert_eq!(jsonl.lines().count(), 2);
+        assert_eq!(
+            load_visited(&config.output_dir),
+            ["https://example.com/a", "https://example.com/b"]
+                .map(String::from)
+                .into()
+        );
+        let json = fs::read_to_string(config.output_dir.join(REPORT_FILE)).unwrap();
+        let saved: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(saved["https://example.com"]["fetched"], 2);
+    }
+
+    #[tokio::test]
+    async fn pages_saved_to_their_own_files_keep_their_markdown() {
+        let paths: Vec<String> = (0..=DEFAULT_FLUSH_EVERY)
+            .map(|i| format!("/p{}", i))
+            .collect();
+        let routes: Vec<(&str, &str)> = paths.iter().map(|path| (path.as_str(), PAGE)).collect();
+        let origin = serve(&routes);
+        let config = CrawlerConfig {
+            output_dir: output_dir("markdown-stream"),
+            urls: paths
+                .iter()
+                .map(|path| format!("{}{}", origin, path))
+                .collect(),
+            ..CrawlerConfig::default()
+        };
+        let result = Crawler::new(config.clone()).run(&origin).await.unwrap();
+        assert_eq!(result.flushed, paths.len());
+        assert!(result.pages.iter().all(|page| !page.markdown.is_empty()));
+        assert_eq!(load_visited(&config.output_dir).len(), paths.len());
+    }
+
+    #[tokio::test]
+    async fn each_domain_keeps_its_own_report() {
+        let (first, second) = (serve(&[("/a", PAGE)]), serve(&[("/b", PAGE)]));
+        let config = CrawlerConfig {
+            output_dir: output_dir("two-domain-report"),
+            urls: vec![format!("{}/a", first), format!("{}/b", second)],
+            ..CrawlerConfig::default()
+        };
+        let crawler = Crawler::new(config.clone());
+        let (a, b) = tokio::join!(crawler.run(&first), crawler.run(&second));
+        a.unwrap();
+        b.unwrap();
+        let json = fs::read_to_string(config.output_dir.join(REPORT_FILE)).unwrap();
+        let reports: serde_json::Value = serde_json::from_str(&json).unwrap();
+        for (origin, path) in [(&first, "/a"), (&second, "/b")] {
+            assert_eq!(reports[origin]["fetched"], 1);
+            assert_eq!(
+                reports[origin]["pages"][0]["url"],
+                format!("{}{}", origin, path)
+            );
+        }
+    }
+
+    #[test]
+    fn broken_links_of_other_domains_are_kept() {
+        let dir = output_dir("two-domain-broken-links");
+        let broken = |url: &str| BrokenLink {
+            url: url.to_string(),
+            error: "HTTP 404".to_string(),
+            linked_from: BTreeSet::from([format!("{}/from", url_host(url))]),
+        };
+        save_broken_links(
+            &dir,
+            "https://a.example",
+            vec![broken("https://a.example/old")],
+        )
+        .unwrap();
+        save_broken_links(
+            &dir,
+            "https://b.example",
+            vec![broken("https://b.example/x")],
+        )
+        .unwrap();
+        save_broken_links(
+            &dir,
+            "https://a.example",
+            vec![broken("https://a.example/new")],
+        )
+        .unwrap();
+        let json = fs::read_to_string(dir.join(BROKEN_LINKS_FILE)).unwrap();
+        let listed: Vec<BrokenLink> = serde_json::from_str(&json).unwrap();
+        let urls: Vec<&str> = listed.iter().map(|link| link.url.as_str()).collect();
+        assert_eq!(urls, ["https://a.example/new", "https://b.example/x"]);
+    }
+
+    #[test]
+    fn each_domain_keeps_its_own_changes() {
+        let dir = output_dir("two-domain-changes");
+        let changes = |domain: &str, added: &str| CrawlChanges {
+            domain: domain.to_string(),
+            added: BTreeSet::from([format!("{}/{}", domain, added)]),
+            modified: BTreeSet::new(),
+            removed: BTreeSet::new(),
+        };
+        save_changes(&dir, &changes("https://a.example", "old")).unwrap();
+        save_changes(&dir, &changes("https://b.example", "x")).unwrap();
+        save_changes(&dir, &changes("https://a.example", "new")).unwrap();
+        let json = fs::read_to_string(dir.join(CHANGES_FILE)).unwrap();
+        let saved: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            saved["https://a.example"]["added"],
+            serde_json::json!(["https://a.example/new"])
+        );
+        assert_eq!(
+            saved["https://b.example"]["added"],
+            serde_json::json!(["https://b.example/x"])
+        );
+    }
+
+    #[tokio::test]
+    async fn native_crawl_counts_the_bytes_of_the_pages_it_visits() {
+        let home = r#"<html><body><a href="/a">A</a></body></html>"#;
+        let origin = serve(&[("/", home), ("/a", PAGE)]);
+        let config = CrawlerConfig {
+            depth: 1,
+            ..CrawlerConfig::default()
+        };
+        let scheduler = Scheduler::new(&config);
+        native_crawl(&origin, &config, &scheduler, Duration::ZERO)
+            .await
+            .unwrap();
+        let (requests, bytes) = scheduler.traffic.snapshot();
+        assert_eq!(requests, 2);
+        assert_eq!(bytes, (home.len() + PAGE.len()) as u64);
+    }
+}
@@ -1,355 +1,375 @@
-use quick_xml::{Reader, events::Event};
-use spider::website::Website;
-use std::collections::HashSet;
-use std::fs::{self, create_dir_all};
-use std::path::Path;
+use futures::stream::{self, StreamExt};
+use indicatif::HumanBytes;
+use log::error;
+use spider::url::Url;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use web_crawler::{
+    Crawler, CrawlerConfig, install_interrupt_handler, interrupted, normalize_domain,
+};
 
-fn main() {
-    // Example usage
-    if let Err(e) = run_crawler("https://spider.cloud") {
-        eprintln!("Crawler failed: {}", e);
-    } else {
-        println!("Crawling completed successfully");
-    }
-}
-
-// Main crawler function
-fn run_crawler(domain: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // Normalize domain
-    let domain = domain.trim_end_matches('/').to_string();
-    eprintln!("Starting crawler for domain: {}", domain);
+const USAGE: &str = "Usage: web-crawler [OPTIONS] <domain> [<domain>...]
 
-    // Step 1: Load robots.txt and get sitemap URLs
-    let sitemap_urls = get_sitemap_urls_from_robots(&domain)?;
-    eprintln!(
-        "Found {} sitemap URLs: {:?}",
-        sitemap_urls.len(),
-        sitemap_urls
-    );
+Example: web-crawler https://spider.cloud example.com --out ./docs
 
-    // Step 2: Get all page URLs
-    let page_urls = if !sitemap_urls.is_empty() {
-        // Load sitemaps recursively
-        let urls = get_all_page_urls_from_sitemaps(&sitemap_urls)?;
-        eprintln!("Collected {} URLs from sitemaps", urls.len());
-        urls
-    } else {
-        // Step 3: Try direct sitemap.xml if no robots.txt
-        let sitemap_url = format!("{}/sitemap.xml", domain);
-        eprintln!("No sitemap URLs in robots.txt, trying: {}", sitemap_url);
-        let sitemap_urls = vec![sitemap_url.clone()];
-        let sitemap_pages = get_all_page_urls_from_sitemaps(&sitemap_urls)?;
+Options:
+  --config <FILE>   Load settings from a TOML file; other flags override it
+  --out <DIR>       Directory to write Markdown files to (default: output)
+  --format <FMT>    markdown: one .md file per page (default); jsonl: append pages to output.jsonl;
+                    text: one .txt file of plain reading text per page;
+                    html-clean: one .html file per page of sanitized, structural HTML
+  --ext <EXT>       Extension for each page's file instead of the format's own (md, txt or html)
+  --single-file <FILE>
+                    Write every page into one Markdown document, in crawl order, with an
+                    H1 of each page's title and URL above it
+  --flush-every <N> Write pages to disk, and to visited.json, every N pages during the crawl
+                    and free their memory (default: every 20, freeing only jsonl and
+                    --single-file pages)
+  --preserve-structure
+                    Mirror URL paths as directories: /docs/intro becomes <out>/docs/intro.md
+  --attempts <N>    Fetch attempts per page before giving up (default: 3)
+  --breaker-failures <N>
+                    Skip a host's URLs after N failed requests in a row, 0 to never skip
+                    (default: 10)
+  --breaker-cooldown <SECS>
+                    How long a failing host is skipped before it is tried again (default: 60)
+  --max-retry-after <SECS>
+                    Longest Retry-After wait honoured after an HTTP 429 (default: 60)
+  --delay <MS>      Pause between requests to a host; a longer robots.txt Crawl-delay wins
+                    (default: 0, or 100 for the native crawl)
+  --jitter <PERCENT>
+                    Vary each of those pauses randomly by up to PERCENT either way (default: 0)
+  --jitter-seed <N> Seed the jitter so a run's pauses can be repeated exactly
+  --concurrency <N> Pages fetched in parallel across all domains (default: 8)
+  --per-host <N>    Pages fetched in parallel from any one host (default: 4)
+  --sitemap-concurrency <N>
+                    Sitemaps fetched in parallel from a sitemap index (default: 4)
+  --sitemap-max-depth <N>
+                    Levels of nested sitemap indexes followed; 0 reads only the top-level
+                    sitemaps (default: 10)
+  --limit <N>       Process at most N pages per domain (default: no limit)
+  --user-agent <UA> User-Agent header sent with every request (default: web-crawler/<version>)
+  --user-agent-rotate <UA>
+                    Take turns sending each UA given this way, one per request (repeatable)
+                    robots.txt is applied as the first of them sees it
+  --proxy <URL>     Send every request through an http://, https:// or socks5:// proxy
+                    (default: HTTPS_PROXY or HTTP_PROXY, if set)
+  --header <H>      Extra request header as 'Name: value', e.g. for a bearer token (repeatable)
+  --max-redirects <N>
+                    Redirect hops followed per request (default: 10)
+  --depth <N>       Deepest URL path, in segments, the native crawl follows; only used
+                    when a site has no sitemap. 0 fetches only the start page (default: 3)
+  --max-links <N>   Stop the native crawl after finding N links (default: no limit)
+  --crawl-prefix <PATH>
+                    Only let the native crawl visit paths under PATH, e.g. /docs (repeatable)
+  --allow-subdomains
+                    Let the native crawl follow links onto subdomains of the site
+  --stay-on-domain  Keep the native crawl on the seed host and its www. twin (default)
+  --timeout <SECS>  Give up on a request after SECS seconds (default: 30)
+  --sitemap <URL>   Read this sitemap instead of looking in robots.txt (repeatable); the
+                    domain can then be left out
+  --url-list <FILE> Fetch exactly the URLs in FILE, one per line, skipping robots.txt and
+                    sitemaps; blank lines and # comments are ignored, and the domains can
+                    then be left out
+  --cross-domain-sitemaps
+                    Also read sitemaps robots.txt lists outside the site's domain
+  --include <GLOB>  Only crawl URL paths matching GLOB, e.g. '/docs/*' (repeatable)
+  --exclude <GLOB>  Never crawl URL paths matching GLOB (repeatable, wins over --include)
+  --strip-boilerplate
+                    Leave out navigation, header, footer and aside content
+  --dedup-content   Skip pages whose Markdown is identical to one already saved
+  --ignore-meta-robots
+                    Save noindex pages and follow nofollow pages' links despite their
+                    meta robots tags
+  --assets          Download images into <out>/images and link the local copies
+  --collect-images  List the image URLs image sitemaps give in <out>/images.txt
+  --include-assets  Save non-HTML files (PDFs, images, archives) under <out>/assets
+                    instead of skipping them
+  --save-html       Keep the fetched HTML of each page beside its output file, as <name>.html
+  --toc             Start each page with a table of contents linking to its headings
+  --keep-dead-links Keep javascript:, empty and # link targets instead of writing just the
+                    link text
+  --heading-offset <N>
+                    Demote every heading by N levels, down to h6, e.g. 1 to keep pages merged
+                    with --single-file under their own H1 (default: 0)
+  --checksums       Write a <page>.md.sha256 beside each Markdown file for sha256sum -c
+  --prefetch-head   Send a HEAD first and skip non-HTML or oversized URLs without
+                    downloading them; falls back to GET where HEAD is refused
+  --words-per-minute <N>
+                    Reading speed for each page's reading_minutes (default: 200)
+  --min-chars <N>   Warn about pages that convert to fewer than N characters and list them
+                    under suspicious_empty in report.json, 0 to never (default: 50)
+  --resume          Skip URLs already saved by an earlier, unfinished run
+  --diff-previous   List the URLs added, modified or removed since the last crawl into
+                    the same directory in <out>/changes.json
+  --since <DATE>    Only crawl pages whose sitemap lastmod is on or after DATE (YYYY-MM-DD);
+                    pages without a lastmod are still crawled
+  --skip-undated    With --since, also skip pages without a lastmod
+  --dry-run         Print the URLs that would be fetched, one per line, and exit
+  -q, --quiet       Hide the progress bar (it is also hidden when stderr is not a terminal)
+  -v, -vv           Log progress (info) or everything (debug); RUST_LOG also works";
 
-        if !sitemap_pages.is_empty() {
-            eprintln!("Collected {} URLs from sitemap.xml", sitemap_pages.len());
-            sitemap_pages
-        } else {
-            // Step 4: Fallback to native crawl
-            eprintln!("No sitemap pages found, falling back to native crawl");
-            native_crawl(&domain)?
-        }
-    };
-
-    eprintln!("Total URLs to process: {}", page_urls.len());
-    if page_urls.is_empty() {
-        eprintln!("Warning: No URLs collected; no Markdown files will be generated");
-    }
-
-    // Step 5-8: Load HTML, convert to Markdown, and save
-    for (i, url) in page_urls.iter().enumerate() {
-        eprintln!("Processing URL {}/{}: {}", i + 1, page_urls.len(), url);
-        match load_html(url, &domain) {
-            Ok(html) => {
-                let markdown = html_to_markdown(&html);
-                if let Err(e) = save_markdown(url, &markdown) {
-                    eprintln!("Failed to save Markdown for {}: {}", url, e);
-                } else {
-                    eprintln!("Saved Markdown for {}", url);
-                }
-            }
-            Err(e) => eprintln!("Failed to load HTML for {}: {}", url, e),
-        }
-    }
-
-    Ok(())
+// Parsed command line: what to crawl and how
+struct CliArgs {
+    domains: Vec<String>,
+    config: CrawlerConfig,
+    verbosity: u8,
+    // Hide the progress bar
+    quiet: bool,
 }
 
-// Step 1: Load robots.txt and extract sitemap URLs
-fn get_sitemap_urls_from_robots(domain: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let robots_url = format!("{}/robots.txt", domain);
-    eprintln!("Fetching robots.txt: {}", robots_url);
-    let mut website = Website::new(&robots_url);
-    website.configuration.depth = 0;
-    // website.configuration.user_agent = Some("Mozilla/5.0 (compatible; MyCrawler/1.0)".to_string());
-    website.scrape();
-
-    let mut sitemap_urls = Vec::new();
-    if let Some(pages) = website.get_pages() {
-        if let Some(page) = pages.first() {
-            let content = page.get_html();
-            sitemap_urls = parse_robots_txt(&content);
-            eprintln!(
-                "Parsed robots.txt, found {} sitemap URLs",
-                sitemap_urls.len()
-            );
-        } else {
-            eprintln!("No pages returned for robots.txt");
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let cli = match parse_args(&args) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
         }
-    } else {
-        eprintln!("Warning: Failed to fetch robots.txt for {}", robots_url);
-    }
-    Ok(sitemap_urls)
-}
-
-// Parse robots.txt to find sitemap URLs
-fn parse_robots_txt(content: &str) -> Vec<String> {
-    let sitemaps: Vec<String> = content
-        .lines()
-        .filter(|line| line.to_lowercase().starts_with("sitemap:"))
-        .map(|line| {
-            line.trim_start_matches(|c: char| c.is_whitespace() || c.to_ascii_lowercase() == 's')
-                .trim()
-                .to_string()
-        })
-        .collect();
-    eprintln!("Extracted sitemaps from robots.txt: {:?}", sitemaps);
-    sitemaps
-}
-
-// Step 2: Load sitemaps recursively and extract page URLs
-fn get_all_page_urls_from_sitemaps(
-    sitemap_urls: &[String],
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let mut all_urls = HashSet::new();
-    for sitemap_url in sitemap_urls {
-        eprintln!("Processing sitemap: {}", sitemap_url);
-        parse_sitemap_recursive(sitemap_url, &mut all_urls)?;
+    };
+    if cli.domains.is_empty() {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
     }
-    eprintln!("Total unique URLs from sitemaps: {}", all_urls.len());
-    Ok(all_urls.into_iter().collect())
-}
+    init_logger(cli.verbosity);
+    install_interrupt_handler();
 
-// Recursive sitemap parsing
-fn parse_sitemap_recursive(
-    sitemap_url: &str,
-    all_urls: &mut HashSet<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut website = Website::new(sitemap_url);
-    website.configuration.depth = 0;
-    // website.configuration.user_agent = Some("Mozilla/5.0 (compatible; MyCrawler/1.0)".to_string());
-    website.scrape();
-
-    if let Some(pages) = website.get_pages() {
-        if let Some(page) = pages.first() {
-            let html = page.get_html();
-            eprintln!(
-                "Fetched sitemap content for {}, length: {} bytes",
-                sitemap_url,
-                html.len()
-            );
-            let mut reader = Reader::from_str(&html);
-
-            let mut buf = Vec::new();
-            let mut in_loc = false;
-            let mut current_url = String::new();
-
-            loop {
-                match reader.read_event_into(&mut buf) {
-                    Ok(Event::Start(e)) => {
-                        if e.name().as_ref() == b"loc" {
-                            in_loc = true;
+    // Progress bars only help someone watching a terminal
+    let show_progress = !cli.quiet && std::io::stderr().is_terminal();
+    let crawler = Crawler::new(cli.config).show_progress(show_progress);
+    let dry_run = crawler.config().dry_run;
+    stream::iter(&cli.domains)
+        .for_each_concurrent(None, |domain| {
+            let crawler = &crawler;
+            async move {
+                let domain = normalize_domain(domain);
+                let started = Instant::now();
+                match crawler.run(&domain).await {
+                    Ok(result) => {
+                        if result.interrupted {
+                            let processed = result.pages.len() + result.failures.len();
+                            println!(
+                                "Interrupted: processed {} of {} URLs for {}, partial results saved",
+                                processed,
+                                processed + result.unprocessed,
+                                domain
+                            );
                         }
-                    }
-                    Ok(Event::Text(e)) => {
-                        if in_loc {
-                            current_url = e.unescape()?.to_string();
-                            eprintln!("Found URL in sitemap: {}", current_url);
-                        }
-                    }
-                    Ok(Event::End(e)) => {
-                        if e.name().as_ref() == b"loc" && !current_url.is_empty() {
-                            in_loc = false;
-                            if current_url.ends_with(".xml") {
-                                // Nested sitemap
-                                eprintln!("Found nested sitemap: {}", current_url);
-                                parse_sitemap_recursive(&current_url, all_urls)?;
-                            } else {
-                                // Page URL
-                                all_urls.insert(current_url.clone());
-                                eprintln!("Added page URL: {}", current_url);
+                        if dry_run {
+                            for url in &result.planned {
+                                println!("{}", url);
                             }
-                            current_url.clear();
+                        } else if !interrupted() {
+                            println!(
+                                "Crawling completed successfully for {} in {:.1?} ({} requests, {})",
+                                domain,
+                                started.elapsed(),
+                                result.requests,
+                                HumanBytes(result.bytes_downloaded)
+                            );
                         }
                     }
-                    Ok(Event::Eof) => break,
-                    Err(e) => {
-                        eprintln!("XML parsing error in sitemap {}: {}", sitemap_url, e);
-                        return Err(Box::new(e));
-                    }
-                    _ => {}
+                    Err(e) => error!("Crawler failed for {}: {}", domain, e),
                 }
-                buf.clear();
             }
-        } else {
-            eprintln!("No pages returned for sitemap {}", sitemap_url);
-        }
-    } else {
-        eprintln!("Warning: Failed to fetch sitemap {}", sitemap_url);
-    }
-
-    Ok(())
-}
-
-// Step 4: Native crawl if no robots.txt or sitemap.xml
-fn native_crawl(domain: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    eprintln!("Starting native crawl for {}", domain);
-    let mut website = Website::new(domain);
-    website.configuration.depth = 3; // Example depth
-    website.configuration.delay = 100; // 100ms delay
-    // website.configuration.user_agent = Some("Mozilla/5.0 (compatible; MyCrawler/1.0)".to_string());
-    // website.configuration.use_browser = get_fetch_mode(domain);
-    website.crawl();
-
-    let urls: Vec<String> = website
-        .get_links()
-        .into_iter()
-        .map(|link| link.to_string())
-        .collect();
-    eprintln!("Native crawl collected {} URLs", urls.len());
-    Ok(urls)
-}
-
-// Step 5: Load HTML from a URL
-fn load_html(url: &str, domain: &str) -> Result<String, Box<dyn std::error::Error>> {
-    eprintln!("Fetching HTML for {}", url);
-    let mut website = Website::new(url);
-    website.configuration.depth = 0;
-    // website.configuration.user_agent = Some("Mozilla/5.0 (compatible; MyCrawler/1.0)".to_string());
-    // website.configuration.use_browser = get_fetch_mode(domain);
-    website.scrape();
-
-    let pages = website
-        .get_pages()
-        .ok_or_else(|| format!("Failed to load HTML for {}", url))?;
-    if let Some(page) = pages.first() {
-        let html = page.get_html();
-        eprintln!(
-            "Successfully fetched HTML for {}, length: {} bytes",
-            url,
-            html.len()
+        })
+        .await;
+    if !dry_run && cli.domains.len() > 1 {
+        println!(
+            "Made {} requests and downloaded {} in total",
+            crawler.requests(),
+            HumanBytes(crawler.bytes_downloaded())
         );
-        Ok(html)
-    } else {
-        Err(format!("No HTML content for {}", url).into())
     }
 }
 
-// Step 6: Determine fetch mode based on domain
-fn get_fetch_mode(domain: &str) -> bool {
-    let use_browser = domain.contains("www.heygoody.com");
-    eprintln!("Using browser mode for {}: {}", domain, use_browser);
-    use_browser // true for browser (SPA), false for HTTP (SSR)
-}
-
-// Step 7: Convert HTML to Markdown
-fn html_to_markdown(html: &str) -> String {
-    eprintln!(
-        "Converting HTML to Markdown, input length: {} bytes",
-        html.len()
-    );
-    let mut markdown = String::new();
-    let mut in_tag: Option<String> = None;
-    let mut current_content = String::new();
-    let mut i = 0;
-    let chars: Vec<char> = html.chars().collect();
+// Split command-line arguments into domains and crawler settings.
+// Flags override the --config file, which overrides the built-in defaults.
+fn parse_args(args: &[String]) -> Result<CliArgs, String> {
+    let config_path = args
+        .iter()
+        .position(|arg| arg == "--config")
+        .map(|i| flag_value::<PathBuf>("--config", args.get(i + 1)))
+        .transpose()?;
+    let config = match config_path {
+        Some(path) => load_config(&path)?,
+        None => CrawlerConfig::default(),
+    };
 
-    while i < chars.len() {
-        if chars[i] == '<' {
-            // Process previous content
-            if !current_content.trim().is_empty() {
-                match in_tag.as_deref() {
-                    Some("h1") => markdown.push_str(&format!("# {}\n\n", current_content.trim())),
-                    Some("h2") => markdown.push_str(&format!("## {}\n\n", current_content.trim())),
-                    Some("p") => markdown.push_str(&format!("{}\n\n", current_content.trim())),
-                    Some("li") => markdown.push_str(&format!("- {}\n", current_content.trim())),
-                    Some("a") => markdown.push_str(&format!(
-                        "[{}]({})",
-                        current_content.trim(),
-                        current_content.trim()
-                    )),
-                    Some("img") => {
-                        markdown.push_str(&format!("![Image]({})\n", current_content.trim()))
-                    }
-                    Some("strong") => markdown.push_str(&format!("**{}**", current_content.trim())),
-                    Some("em") => markdown.push_str(&format!("*{}*", current_content.trim())),
-                    Some("blockquote") => {
-                        markdown.push_str(&format!("> {}\n\n", current_content.trim()))
-                    }
-                    _ => markdown.push_str(&format!("{}\n", current_content.trim())),
-                }
+    let mut cli = CliArgs {
+        domains: Vec::new(),
+        config,
+        verbosity: 0,
+        quiet: false,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Already loaded above
+            "--config" => {
+                args.next();
             }
-            current_content.clear();
-
-            // Parse tag
-            let mut tag = String::new();
-            i += 1;
-            while i < chars.len() && chars[i] != '>' && chars[i] != ' ' {
-                tag.push(chars[i]);
-                i += 1;
+            "--out" => cli.config.output_dir = flag_value(arg, args.next())?,
+            "--format" => cli.config.format = flag_value(arg, args.next())?,
+            "--ext" => cli.config.extension = Some(flag_value(arg, args.next())?),
+            "--single-file" => cli.config.single_file = Some(flag_value(arg, args.next())?),
+            "--flush-every" => cli.config.flush_every = flag_value(arg, args.next())?,
+            "--preserve-structure" => cli.config.preserve_structure = true,
+            "--attempts" => cli.config.fetch_attempts = flag_value(arg, args.next())?,
+            "--breaker-failures" => cli.config.breaker_failures = flag_value(arg, args.next())?,
+            "--breaker-cooldown" => {
+                cli.config.breaker_cooldown_secs = flag_value(arg, args.next())?
             }
-            while i < chars.len() && chars[i] != '>' {
-                i += 1;
+            "--max-retry-after" => cli.config.max_retry_after_secs = flag_value(arg, args.next())?,
+            "--delay" => cli.config.delay_ms = Some(flag_value(arg, args.next())?),
+            "--jitter" => cli.config.jitter_percent = flag_value(arg, args.next())?,
+            "--jitter-seed" => cli.config.jitter_seed = Some(flag_value(arg, args.next())?),
+            "--concurrency" => cli.config.concurrency = flag_value(arg, args.next())?,
+            "--per-host" => cli.config.per_host = flag_value(arg, args.next())?,
+            "--sitemap-concurrency" => {
+                cli.config.sitemap_concurrency = flag_value(arg, args.next())?
             }
-            i += 1; // Move past '>'
-
-            if tag.starts_with('/') {
-                in_tag = None;
-            } else {
-                match tag.as_str() {
-                    "h1" | "h2" | "p" | "li" | "a" | "img" | "strong" | "em" | "blockquote" => {
-                        in_tag = Some(tag);
-                    }
-                    "br" => markdown.push_str("\n"),
-                    "ul" | "ol" => markdown.push_str("\n"),
-                    _ => in_tag = None,
-                }
+            "--sitemap-max-depth" => cli.config.sitemap_max_depth = flag_value(arg, args.next())?,
+            "--limit" => cli.config.max_pages = Some(flag_value(arg, args.next())?),
+            "--user-agent" => cli.config.user_agent = flag_value(arg, args.next())?,
+            "--user-agent-rotate" => cli.config.user_agents.push(flag_value(arg, args.next())?),
+            "--proxy" => cli.config.proxy = Some(flag_value(arg, args.next())?),
+            "--header" => {
+                let header: String = flag_value(arg, args.next())?;
+                let (name, value) = header
+                    .split_once(':')
+                    .ok_or_else(|| "--header must look like \"Name: value\"".to_string())?;
+                cli.config
+                    .headers
+                    .insert(name.trim().to_string(), value.trim().to_string());
             }
-        } else {
-            current_content.push(chars[i]);
-            i += 1;
+            "--max-redirects" => cli.config.max_redirects = flag_value(arg, args.next())?,
+            "--depth" => cli.config.depth = flag_value(arg, args.next())?,
+            "--timeout" => cli.config.timeout_secs = flag_value(arg, args.next())?,
+            "--max-links" => cli.config.max_links = Some(flag_value(arg, args.next())?),
+            "--crawl-prefix" => cli
+                .config
+                .crawl_prefixes
+                .push(flag_value(arg, args.next())?),
+            "--allow-subdomains" => cli.config.allow_subdomains = true,
+            "--stay-on-domain" => cli.config.allow_subdomains = false,
+            "--strip-boilerplate" => cli.config.strip_boilerplate = true,
+            "--dedup-content" => cli.config.dedup_content = true,
+            "--ignore-meta-robots" => cli.config.meta_robots = false,
+            "--assets" => cli.config.assets = true,
+            "--collect-images" => cli.config.collect_images = true,
+            "--include-assets" => cli.config.include_assets = true,
+            "--save-html" => cli.config.save_html = true,
+            "--toc" => cli.config.toc = true,
+            "--keep-dead-links" => cli.config.keep_dead_links = true,
+            "--heading-offset" => cli.config.heading_offset = flag_value(arg, args.next())?,
+            "--checksums" => cli.config.checksums = true,
+            "--prefetch-head" => cli.config.prefetch_head = true,
+            "--words-per-minute" => cli.config.words_per_minute = flag_value(arg, args.next())?,
+            "--min-chars" => cli.config.min_content_chars = flag_value(arg, args.next())?,
+            "--resume" => cli.config.resume = true,
+            "--diff-previous" => cli.config.diff_previous = true,
+            "--since" => cli.config.since = Some(flag_value(arg, args.next())?),
+            "--skip-undated" => cli.config.skip_undated = true,
+            "--dry-run" => cli.config.dry_run = true,
+            "--sitemap" => cli.config.sitemaps.push(flag_value(arg, args.next())?),
+            "--url-list" => {
+                let path: PathBuf = flag_value(arg, args.next())?;
+                cli.config.urls.extend(read_url_list(&path)?);
+            }
+            "--cross-domain-sitemaps" => cli.config.cross_domain_sitemaps = true,
+            "--include" => cli.config.include.push(flag_value(arg, args.next())?),
+            "--exclude" => cli.config.exclude.push(flag_value(arg, args.next())?),
+            "-q" | "--quiet" => cli.quiet = true,
+            "-v" => cli.verbosity += 1,
+            "-vv" => cli.verbosity += 2,
+            flag if flag.starts_with('-') => return Err(format!("Unknown option: {}", flag)),
+            domain => cli.domains.push(domain.to_string()),
         }
     }
-
-    // Handle remaining content
-    if !current_content.trim().is_empty() {
-        markdown.push_str(&format!("{}\n", current_content.trim()));
+    // Catch a malformed header or proxy now rather than on every request
+    cli.config.request_headers()?;
+    cli.config.proxy_url()?;
+    // Sitemaps or page URLs alone are enough to know which sites to crawl
+    if cli.domains.is_empty() {
+        for sitemap in &cli.config.sitemaps {
+            let origin = Url::parse(sitemap)
+                .map_err(|_| format!("Invalid --sitemap URL: {}", sitemap))?
+                .origin()
+                .ascii_serialization();
+            if !cli.domains.contains(&origin) {
+                cli.domains.push(origin);
+            }
+        }
+        for url in &cli.config.urls {
+            let origin = Url::parse(url)
+                .map_err(|_| format!("Invalid URL: {}", url))?
+                .origin()
+                .ascii_serialization();
+            if !cli.domains.contains(&origin) {
+                cli.domains.push(origin);
+            }
+        }
     }
+    Ok(cli)
+}
 
-    eprintln!(
-        "Generated Markdown, output length: {} bytes",
-        markdown.len()
-    );
-    markdown
+// The http(s) URLs of a --url-list file, one per line, leaving out blank lines and
+// # comments
+fn read_url_list(path: &Path) -> Result<Vec<String>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read URL list {}: {}", path.display(), e))?;
+    let mut urls = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match Url::parse(line) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => urls.push(line.to_string()),
+            _ => {
+                return Err(format!(
+                    "Invalid URL on line {} of {}: {}",
+                    i + 1,
+                    path.display(),
+                    line
+                ));
+            }
+        }
+    }
+    Ok(urls)
 }
 
-// Step 8: Save Markdown to file
-fn save_markdown(url: &str, markdown: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // Create output directory
-    let output_dir = "output";
-    create_dir_all(output_dir)?;
+// Read crawler settings from a TOML file; missing keys keep their defaults
+fn load_config(path: &Path) -> Result<CrawlerConfig, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+    toml::from_str(&content).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+}
 
-    // Generate file name from URL
-    let file_name = url
-        .trim_end_matches('/')
-        .split('/')
-        .last()
-        .unwrap_or("index")
-        .to_string()
-        + ".md";
-    let file_path = Path::new(output_dir).join(&file_name);
+// Parse the value that follows a flag
+fn flag_value<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} requires a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {} value: {}", flag, value))
+}
 
-    // Save file
-    fs::write(&file_path, markdown)?;
-    eprintln!("Saved Markdown file: {}", file_path.display());
-    Ok(())
+// Quiet by default (warnings and errors only); -v/-vv raise our own log level
+fn init_logger(verbosity: u8) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
+    match verbosity {
+        0 => {}
+        1 => {
+            builder.filter_module(module_path!(), log::LevelFilter::Info);
+        }
+        _ => {
+            builder.filter_module(module_path!(), log::LevelFilter::Debug);
+        }
+    }
+    builder.init();
 }